@@ -0,0 +1,102 @@
+//! Measures how long the table solver takes, and how much memory it needs, on a few inputs.
+//!
+//! Run with `cargo bench -p fuzzy`. This uses a plain `main` rather than a benchmark framework,
+//! as we mostly care about peak memory, which we track with a counting allocator.
+//!
+//! Shrinking table nodes from 144 to 16 bytes, so each node only keeps its score, the index of
+//! its optimal step and its status, while the state of nodes we are still working on lives in
+//! `Frame`s, changed the numbers on one machine (release build) from the baseline to:
+//!
+//! | input                | baseline time | baseline peak | new time | new peak |
+//! |----------------------|---------------|---------------|----------|----------|
+//! | mit license          |        904 ms |        190 MB |   163 ms |    18 MB |
+//! | generated 2000 chars |       2635 ms |        680 MB |   624 ms |    62 MB |
+//! | generated 4000 chars |      13575 ms |       2723 MB |  2734 ms |   247 MB |
+//!
+//! Peak memory still grows with pattern length times text length, so the 4000 character input
+//! needs about 250 MB.
+
+use fuzzy::Options;
+use fuzzy::table_solution::Node;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct CountingAlloc;
+
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let current = CURRENT.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(current, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        CURRENT.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+/// Generates a literal pattern of roughly this many characters, and a text with a few changes.
+///
+/// We use a simple linear congruential generator, so every run matches the same inputs.
+fn generated(len: usize, changes: usize) -> (String, String) {
+    let words = ["fn", "let", "mut", "x", "=", "1;", "value", "return", "(", ")"];
+    let mut seed: u64 = 42;
+    let mut next = |n: usize| {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (seed >> 33) as usize % n
+    };
+    let mut pattern = String::new();
+    while pattern.len() < len {
+        pattern.push_str(words[next(words.len())]);
+        pattern.push(' ');
+    }
+    let mut text: Vec<char> = pattern.chars().collect();
+    for _ in 0..changes {
+        let ix = next(text.len());
+        text[ix] = '#';
+    }
+    let pattern = pattern.replace('(', "\\(").replace(')', "\\)");
+    (pattern, text.into_iter().collect())
+}
+
+fn bench(name: &str, pattern: &str, text: &str, options: &Options) {
+    let before = CURRENT.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let start = Instant::now();
+    let result = fuzzy::fuzzy_match(pattern.to_string(), text.to_string(), options);
+    let elapsed = start.elapsed();
+    let peak = PEAK.load(Ordering::Relaxed) - before;
+    let score = result.map_or_else(|err| format!("{:?}", err), |output| output.score.to_string());
+    println!(
+        "{:<24} score {:>6} {:>10.1} ms {:>10.1} MB",
+        name,
+        score,
+        elapsed.as_secs_f64() * 1000.0,
+        peak as f64 / (1024.0 * 1024.0),
+    );
+}
+
+fn main() {
+    println!("node size: {} bytes", std::mem::size_of::<Node>());
+
+    let mit_pattern = include_str!("../../examples/mit_pattern");
+    let license = include_str!("../../LICENSE");
+    bench("mit license", mit_pattern, license, &Options::default());
+
+    let (pattern, text) = generated(2000, 10);
+    bench("generated 2000 chars", &pattern, &text, &Options::default());
+
+    let (pattern, text) = generated(4000, 20);
+    bench("generated 4000 chars", &pattern, &text, &Options::default());
+}
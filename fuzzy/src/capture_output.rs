@@ -0,0 +1,206 @@
+use crate::{Group, Match, Step};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The text captured by each `()` group in the optimal match, similar to regex `Captures`.
+///
+/// Group 0 always captures the entire matched text. A group has no capture if the optimal match never
+/// entered it, e.g. when it sits in an alternative branch that was not taken. If the optimal match
+/// enters a group several times, e.g. because it is inside a repetition, we report the last
+/// capture, just like regex does.
+///
+/// Named groups, e.g. `(?P<version>[0-9.]*)`, can also be looked up by name.
+pub struct Captures {
+    pub groups: Vec<Option<Capture>>,
+    pub names: Vec<Option<String>>,
+}
+
+/// The text captured by a single group.
+///
+/// Because the match is inexact, the captured text includes any text characters that were skipped
+/// while inside the group, but does not include any skipped pattern characters.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Capture {
+    /// The index of the first captured character in the text.
+    pub start: usize,
+    /// The index after the last captured character in the text.
+    pub end: usize,
+    pub text: String,
+}
+
+impl Captures {
+    pub fn new(trace: &[Step<Match, char>], pattern_groups: &[Group]) -> Self {
+        Self::new_at(trace, pattern_groups, 0)
+    }
+
+    /// Finds the captures for a trace which starts at this index in the text, rather than at the
+    /// start of the text.
+    pub fn new_at(trace: &[Step<Match, char>], pattern_groups: &[Group], offset: usize) -> Self {
+        let num_groups = pattern_groups.iter().map(|group| group.index + 1).max().unwrap_or(1);
+        let mut names = vec![None; num_groups];
+        for group in pattern_groups.iter() {
+            names[group.index] = group.name.clone();
+        }
+
+        let mut text = vec![];
+        let mut open = vec![];
+        let mut groups = vec![None; num_groups];
+        for step in trace.iter() {
+            match step {
+                Step::Hit(_, c) | Step::Replace(_, c) | Step::SkipText(c) => text.push(*c),
+                Step::Transpose(_, _, c1, c2) => text.extend([*c1, *c2]),
+                Step::StartCapture(index) => open.push((*index, text.len())),
+                Step::StopCapture(index) => {
+                    // capture groups are always properly nested, so the last group opened is
+                    // always the next group closed
+                    if let Some((start_index, start)) = open.pop() {
+                        debug_assert_eq!(start_index, *index);
+                        Self::set(&mut groups, *index, Self::capture(&text, start, offset));
+                    }
+                }
+                Step::SkipPattern(_) | Step::FailLook(_) => {}
+            }
+        }
+        Self::set(&mut groups, 0, Self::capture(&text, 0, offset));
+        Captures { groups, names }
+    }
+
+    /// Returns the capture for the group with this index, if it captured anything.
+    pub fn get(&self, index: usize) -> Option<&Capture> {
+        self.groups.get(index).and_then(|group| group.as_ref())
+    }
+
+    /// Returns the capture for the group with this name, if it captured anything.
+    pub fn name(&self, name: &str) -> Option<&Capture> {
+        self.names.iter()
+            .position(|opt_name| opt_name.as_deref() == Some(name))
+            .and_then(|index| self.get(index))
+    }
+
+    /// Returns the capture for every named group that captured something.
+    pub fn named(&self) -> BTreeMap<&str, &Capture> {
+        self.names.iter().zip(self.groups.iter())
+            .filter_map(|(opt_name, group)| Some((opt_name.as_deref()?, group.as_ref()?)))
+            .collect()
+    }
+
+    fn capture(text: &[char], start: usize, offset: usize) -> Capture {
+        let end = text.len();
+        Capture { start: start + offset, end: end + offset, text: text[start..end].iter().collect() }
+    }
+
+    fn set(groups: &mut Vec<Option<Capture>>, index: usize, capture: Capture) {
+        if groups.len() <= index {
+            groups.resize(index + 1, None);
+        }
+        groups[index] = Some(capture);
+    }
+}
+
+/// Displays the text captured by each group after group 0, one group per line.
+///
+/// Each line starts with the group name, or the group index if the group does not have a name.
+impl fmt::Display for Captures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, group) in self.groups.iter().enumerate().skip(1) {
+            if index > 1 {
+                writeln!(f)?;
+            }
+            match &self.names[index] {
+                Some(name) => write!(f, "{}:", name)?,
+                None => write!(f, "{}:", index)?,
+            }
+            if let Some(capture) = group {
+                write!(f, " {:?}", capture.text)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Element, Pattern};
+    use crate::test_cases::{TestCase, capture, lits, named_capture, pattern};
+
+    #[test]
+    fn test_new_match_lit_2() {
+        let test_case = TestCase::match_lit_2();
+        let actual = Captures::new(&test_case.trace, &groups(test_case.pattern));
+        assert_eq!(vec![Some(captured(0, "ab"))], actual.groups);
+        assert_eq!("", format!("{}", actual));
+    }
+
+    #[test]
+    fn test_new_match_capture_1() {
+        let test_case = TestCase::match_capture_1();
+        let actual = Captures::new(&test_case.trace, &groups(test_case.pattern));
+        assert_eq!(vec![Some(captured(0, "abc")), Some(captured(1, "b"))], actual.groups);
+        assert_eq!("1: \"b\"", format!("{}", actual));
+    }
+
+    #[test]
+    fn test_new_match_capture_2() {
+        let test_case = TestCase::match_capture_2();
+        let actual = Captures::new(&test_case.trace, &groups(test_case.pattern));
+        assert_eq!(vec![Some(captured(0, "cd")), None, Some(captured(0, "cd"))], actual.groups);
+        assert_eq!("1:\n2: \"cd\"", format!("{}", actual));
+    }
+
+    #[test]
+    fn test_new_match_capture_3() {
+        let test_case = TestCase::match_capture_3();
+        let actual = Captures::new(&test_case.trace, &groups(test_case.pattern));
+        assert_eq!(
+            vec![Some(captured(0, "abab")), Some(captured(2, "ab")), Some(captured(3, "b"))],
+            actual.groups
+        );
+    }
+
+    #[test]
+    fn test_new_fail_capture_1() {
+        let test_case = TestCase::fail_capture_1();
+        let actual = Captures::new(&test_case.trace, &groups(test_case.pattern));
+        assert_eq!(vec![Some(captured(0, "axbd")), Some(captured(1, "xb"))], actual.groups);
+    }
+
+    #[test]
+    fn test_new_at_fail_capture_1() {
+        let test_case = TestCase::fail_capture_1();
+        let actual = Captures::new_at(&test_case.trace, &groups(test_case.pattern), 2);
+        assert_eq!(vec![Some(captured(2, "axbd")), Some(captured(3, "xb"))], actual.groups);
+    }
+
+    #[test]
+    fn test_new_match_named_capture_1() {
+        let test_case = TestCase::match_named_capture_1();
+        let actual = Captures::new(&test_case.trace, &groups(test_case.pattern));
+        assert_eq!(Some(&captured(0, "ab")), actual.name("key"));
+        assert_eq!(Some(&captured(4, "d")), actual.name("value"));
+        assert_eq!(None, actual.name("missing"));
+        assert_eq!(
+            BTreeMap::from([("key", &captured(0, "ab")), ("value", &captured(4, "d"))]),
+            actual.named()
+        );
+        assert_eq!("key: \"ab\"\n2: \"c\"\nvalue: \"d\"", format!("{}", actual));
+    }
+
+    #[test]
+    fn test_new_unmatched_groups() {
+        let pattern = pattern(vec![capture(1, lits("a")), named_capture(2, "b", lits("b"))]);
+        let actual = Captures::new(&[], &groups(pattern));
+        assert_eq!(vec![Some(captured(0, "")), None, None], actual.groups);
+        assert_eq!(None, actual.name("b"));
+        assert_eq!("1:\nb:", format!("{}", actual));
+    }
+
+    fn groups(pattern: Pattern<Element>) -> Vec<Group> {
+        pattern.desugar().groups()
+    }
+
+    fn captured(start: usize, text: &str) -> Capture {
+        let end = start + text.chars().count();
+        Capture { start, end, text: text.to_string() }
+    }
+}
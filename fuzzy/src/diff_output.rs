@@ -1,4 +1,4 @@
-use crate::{Match, Step};
+use crate::{Look, Match, Step};
 use std::fmt;
 
 // NOTE: because we do character by character diffs, this won't be the real diff format
@@ -22,6 +22,14 @@ pub struct DiffOutput {
 pub enum Chunk {
     Same(Same),
     Diff(Diff),
+    /// Consecutive characters which only hit case-insensitive pattern literals by ignoring case.
+    Case(Diff),
+    /// Consecutive pattern characters replaced by text characters, in the same order.
+    Replace(Diff),
+    /// Pairs of pattern characters matching text characters in reverse order.
+    Swap(Diff),
+    /// A zero-width assertion which does not hold at this point in the text.
+    FailLook(Look),
 }
 
 impl Chunk {
@@ -36,6 +44,18 @@ impl Chunk {
     fn new_taken(c: char) -> Self {
         Chunk::Diff(Diff { taken: vec![c], added: vec![] })
     }
+
+    fn new_case(p: char, c: char) -> Self {
+        Chunk::Case(Diff { taken: vec![p], added: vec![c] })
+    }
+
+    fn new_replace(p: char, c: char) -> Self {
+        Chunk::Replace(Diff { taken: vec![p], added: vec![c] })
+    }
+
+    fn new_swap(p1: char, p2: char, c1: char, c2: char) -> Self {
+        Chunk::Swap(Diff { taken: vec![p1, p2], added: vec![c1, c2] })
+    }
 }
 
 #[derive(Eq, PartialEq, Debug)]
@@ -54,13 +74,30 @@ impl DiffOutput {
         for step in trace.iter() {
             let current_chunk = chunks.last_mut();
             match (step, current_chunk) {
+                (Step::Hit(Match::CaselessLit(p), c), Some(Chunk::Case(case))) if p != c => {
+                    case.taken.push(*p);
+                    case.added.push(*c);
+                }
+                (Step::Hit(Match::CaselessLit(p), c), _) if p != c => chunks.push(Chunk::new_case(*p, *c)),
+                (Step::Replace(p, c), Some(Chunk::Replace(replace))) => {
+                    replace.taken.push(Self::taken(p));
+                    replace.added.push(*c);
+                }
+                (Step::Replace(p, c), _) => chunks.push(Chunk::new_replace(Self::taken(p), *c)),
+                (Step::Transpose(p1, p2, c1, c2), Some(Chunk::Swap(swap))) => {
+                    swap.taken.extend([Self::taken(p1), Self::taken(p2)]);
+                    swap.added.extend([*c1, *c2]);
+                }
+                (Step::Transpose(p1, p2, c1, c2), _) =>
+                    chunks.push(Chunk::new_swap(Self::taken(p1), Self::taken(p2), *c1, *c2)),
+                (Step::FailLook(look),               _)                       => chunks.push(Chunk::FailLook(*look)),
                 (Step::Hit(_, c),                    Some(Chunk::Same(same))) => same.text.push(*c),
                 (Step::Hit(_, c),                    _)                       => chunks.push(Chunk::new_same(*c)),
                 (Step::SkipText(c),                  Some(Chunk::Diff(diff))) => diff.added.push(*c),
                 (Step::SkipText(c),                  _)                       => chunks.push(Chunk::new_added(*c)),
-                (Step::SkipPattern(Match::Lit(c)),   Some(Chunk::Diff(diff))) => diff.taken.push(*c),
+                (Step::SkipPattern(Match::Lit(c) | Match::CaselessLit(c)), Some(Chunk::Diff(diff))) => diff.taken.push(*c),
                 (Step::SkipPattern(Match::Class(_)), Some(Chunk::Diff(diff))) => diff.taken.push(ANY),
-                (Step::SkipPattern(Match::Lit(c)),   _)                       => chunks.push(Chunk::new_taken(*c)),
+                (Step::SkipPattern(Match::Lit(c) | Match::CaselessLit(c)), _) => chunks.push(Chunk::new_taken(*c)),
                 (Step::SkipPattern(Match::Class(_)), _)                       => chunks.push(Chunk::new_taken(ANY)),
                 _                                                             => {},
             }
@@ -82,6 +119,14 @@ impl fmt::Display for DiffOutput {
                     write!(f, "{{+{}+}}", Self::as_str(&diff.added))?,
                 Chunk::Diff(diff) =>
                     write!(f, "[-{}-]{{+{}+}}", Self::as_str(&diff.taken), Self::as_str(&diff.added))?,
+                Chunk::Case(case) =>
+                    write!(f, "[~{}~]{{~{}~}}", Self::as_str(&case.taken), Self::as_str(&case.added))?,
+                Chunk::Replace(replace) =>
+                    write!(f, "[={}=]{{={}=}}", Self::as_str(&replace.taken), Self::as_str(&replace.added))?,
+                Chunk::Swap(swap) =>
+                    write!(f, "[%{}%]{{%{}%}}", Self::as_str(&swap.taken), Self::as_str(&swap.added))?,
+                Chunk::FailLook(look) =>
+                    write!(f, "[!{}!]", look)?,
             }
         }
         Ok(())
@@ -92,6 +137,13 @@ impl DiffOutput {
     fn as_str(vec: &Vec<char>) -> String {
         vec.into_iter().collect()
     }
+
+    fn taken(patt: &Match) -> char {
+        match patt {
+            Match::Lit(c) | Match::CaselessLit(c) => *c,
+            Match::Class(_) => ANY,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -218,4 +270,76 @@ mod tests {
         let actual = format!("{}", DiffOutput::new(&test_case.score, &test_case.trace));
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn test_new_fail_look_1() {
+        let test_case = TestCase::fail_look_1();
+        let expected = "a[!(?m:^)!]b";
+        let actual = format!("{}", DiffOutput::new(&test_case.score, &test_case.trace));
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_new_match_caseless_1() {
+        let test_case = TestCase::match_caseless_1();
+        let expected = "[~H~]{~h~}i!";
+        let actual = format!("{}", DiffOutput::new(&test_case.score, &test_case.trace));
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_new_fail_costs_hit_case() {
+        let test_case = TestCase::fail_costs_hit_case();
+        let expected = "a[~bc~]{~BC~}";
+        let actual = format!("{}", DiffOutput::new(&test_case.score, &test_case.trace));
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_new_fail_replace_1() {
+        let test_case = TestCase::fail_replace_1();
+        let expected = "a[=b=]{=x=}c";
+        let actual = format!("{}", DiffOutput::new(&test_case.score, &test_case.trace));
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_new_fail_replace_2() {
+        let test_case = TestCase::fail_replace_2();
+        let expected = "[=?=]{=a=}b";
+        let actual = format!("{}", DiffOutput::new(&test_case.score, &test_case.trace));
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_new_fail_transpose_1() {
+        let test_case = TestCase::fail_transpose_1();
+        let expected = "t[%he%]{%eh%}";
+        let actual = format!("{}", DiffOutput::new(&test_case.score, &test_case.trace));
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_new_fail_transpose_2() {
+        let test_case = TestCase::fail_transpose_2();
+        let expected = "rec[%e?%]{%ie%}pt";
+        let actual = format!("{}", DiffOutput::new(&test_case.score, &test_case.trace));
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_new_fail_words_1() {
+        let test_case = TestCase::fail_words_1();
+        let expected = "hi [-you-]{+yo+}";
+        let actual = format!("{}", DiffOutput::new(&test_case.score, &test_case.trace));
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn test_new_fail_lines_1() {
+        let test_case = TestCase::fail_lines_1();
+        let expected = "a\n[-b\n-]{+x\n+}c\n";
+        let actual = format!("{}", DiffOutput::new(&test_case.score, &test_case.trace));
+        assert_eq!(expected, actual);
+    }
 }
@@ -14,18 +14,24 @@ pub enum Error {
     PatternUnsupported(String),
     #[error("PATTERN sets a regex bound that is too large for this architecture")]
     RegexBoundTooLarge,
+    #[error("PATTERN cannot match TEXT without skipping a protected character")]
+    NoPossibleMatch,
+    #[error("PATTERN cannot match TEXT with a cost of at most {0}")]
+    NoMatchWithinMaxCost(usize),
+    #[error("PATTERN cannot match TEXT within a band of {0} characters")]
+    NoMatchWithinBand(usize),
     #[error("Gave up matching PATTERN against TEXT after {0} steps")]
     ExceededMaxSteps(usize),
-    #[error("Internal error: node {0} is neiher working nor done after being processed")]
-    NoNodeProgress(String),
+    #[error("Matching PATTERN against TEXT needs more than the maximum of {0} nodes")]
+    ExceededMaxNodes(usize),
+    #[error("Finding several matches needs the table solver, without a band")]
+    SeveralMatchesNeedTable,
     #[error("Internal error: could not find NodeType for non-end Ix {0}")]
     NoNodeType(String),
-    #[error("Internal error: can only initialise node {0} once")]
-    CannotInitialiseNode(String),
-    #[error("Internal error: can only update node {0} if it is initialised and not done")]
-    CannotUpdateNode(String),
-    #[error("Internal error: cannot retrieve node field(s) '{0}' unless node is {1}")]
-    CannotGetNodeField(&'static str, &'static str),
+    #[error("Internal error: node {0} depends on its own score")]
+    CyclicNode(String),
+    #[error("Internal error: transposition from {0} does not swap two characters")]
+    UnexpectedTransposition(String),
     #[error("Internal error: final state does not contain all output information")]
     IncompleteFinalState,
 }
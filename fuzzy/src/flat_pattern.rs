@@ -1,4 +1,4 @@
-use crate::{Class, ElementCore, Match, Pattern};
+use crate::{Bounds, Class, ElementCore, Group, Look, Match, Pattern, Tokens};
 
 /// A flattened alternative to [`Pattern`], so we can index our position with a single number.
 pub struct FlatPattern {
@@ -18,7 +18,7 @@ impl FlatPattern {
 
 impl FlatPattern {
     pub fn new(pattern: &Pattern<ElementCore>) -> Self {
-        Self::custom(pattern, 0)
+        Self::custom(pattern, 0, Tokens::Chars, false)
     }
 
     /// This constructor includes custom flags to change the pattern we generate.
@@ -38,50 +38,94 @@ impl FlatPattern {
     ///
     /// (In this example, < and > represent the start and end of repetitions.)
     /// ```
-    pub fn custom(pattern: &Pattern<ElementCore>, rep_incr: usize) -> Self {
+    ///
+    /// The `tokens` flag controls how we split consecutive pattern literals into [`Flat::Token`]s.
+    /// If the `ignore_whitespace` flag is set, we first replace each run of whitespace literals
+    /// with a single [`Flat::Whitespace`].
+    pub fn custom(pattern: &Pattern<ElementCore>, rep_incr: usize, tokens: Tokens, ignore_whitespace: bool) -> Self {
         let mut elems = vec![];
-        Self::pattern_patts(&mut elems, &pattern, 1, rep_incr);
+        let flags = Flags { rep_incr, tokens, ignore_whitespace };
+        Self::pattern_patts(&mut elems, &pattern, 1, &flags);
         FlatPattern { elems }
     }
 
-    fn pattern_patts(result: &mut Vec<Flat>, pattern: &Pattern<ElementCore>, reps: usize, rep_incr: usize) {
+    fn pattern_patts(result: &mut Vec<Flat>, pattern: &Pattern<ElementCore>, reps: usize, flags: &Flags) {
+        let mut lits = vec![];
         for elem in pattern.elems.iter() {
-            Self::elem_patts(result, elem, reps, rep_incr)
+            match elem {
+                ElementCore::Match(Match::Lit(c)) => lits.push(*c),
+                _ => {
+                    Self::lit_patts(result, &lits, reps, flags);
+                    lits.clear();
+                    Self::elem_patts(result, elem, reps, flags)
+                }
+            }
+        }
+        Self::lit_patts(result, &lits, reps, flags);
+    }
+
+    fn lit_patts(result: &mut Vec<Flat>, lits: &[char], reps: usize, flags: &Flags) {
+        if !flags.ignore_whitespace {
+            return Self::token_patts(result, lits, reps, flags);
+        }
+        for run in lits.chunk_by(|a, b| a.is_whitespace() == b.is_whitespace()) {
+            if run[0].is_whitespace() {
+                Self::single_patt(result, Flat::Whitespace(run.to_vec()), reps);
+            } else {
+                Self::token_patts(result, run, reps, flags);
+            }
+        }
+    }
+
+    fn token_patts(result: &mut Vec<Flat>, lits: &[char], reps: usize, flags: &Flags) {
+        let ends = flags.tokens.ends(lits);
+        let mut start = 0;
+        while start < lits.len() {
+            let end = ends[start];
+            let elem = match &lits[start..end] {
+                [c] => Flat::Lit(*c),
+                token => Flat::Token(token.to_vec()),
+            };
+            Self::single_patt(result, elem, reps);
+            start = end;
         }
     }
 
-    fn elem_patts(result: &mut Vec<Flat>, elem: &ElementCore, reps: usize, rep_incr: usize) {
+    fn elem_patts(result: &mut Vec<Flat>, elem: &ElementCore, reps: usize, flags: &Flags) {
         match elem {
             ElementCore::Match(Match::Lit(c)) =>
                 Self::single_patt(result, Flat::Lit(*c), reps),
+            ElementCore::Match(Match::CaselessLit(c)) =>
+                Self::single_patt(result, Flat::CaselessLit(*c), reps),
             ElementCore::Match(Match::Class(class)) =>
                 Self::single_patt(result, Flat::Class(class.clone()), reps),
-            ElementCore::Capture(inner) => {
-                Self::single_patt(result, Flat::GroupStart, reps);
-                Self::pattern_patts(result, inner, reps, rep_incr);
-                Self::single_patt(result, Flat::GroupEnd, reps);
+            ElementCore::Look(look) =>
+                Self::single_patt(result, Flat::Look(*look), reps),
+            ElementCore::Capture(group, inner) => {
+                Self::single_patt(result, Flat::GroupStart(group.clone()), reps);
+                Self::pattern_patts(result, inner, reps, flags);
+                Self::single_patt(result, Flat::GroupEnd(group.index), reps);
             }
-            // repetition minimum bounds are handled by repeating the inner pattern in front of the
-            // unbounded repetition. e.g. converting z+ into zz*, or (ab){2,} into abab(ab)*
-            ElementCore::Repetition(repetition) => {
-                let next_reps = reps + rep_incr;
+            // we keep the bounds on the repetition, and the solver counts iterations to respect them
+            ElementCore::Repetition(bounds, repetition) => {
+                let next_reps = reps + flags.rep_incr;
                 let start_ix = result.len();
-                Self::single_patt(result, Flat::RepetitionStart(0), reps);
-                Self::pattern_patts(result, repetition, next_reps, rep_incr);
+                Self::single_patt(result, Flat::RepetitionStart(0, *bounds), reps);
+                Self::pattern_patts(result, repetition, next_reps, flags);
                 let end_ix = result.len();
-                Self::single_patt(result, Flat::RepetitionEnd(0), next_reps);
+                Self::single_patt(result, Flat::RepetitionEnd(0, *bounds), next_reps);
 
                 let off = end_ix - start_ix;
-                Self::update_patt(result, Flat::RepetitionStart(off), start_ix, reps);
-                Self::update_patt(result, Flat::RepetitionEnd(off), end_ix, next_reps);
+                Self::update_patt(result, Flat::RepetitionStart(off, *bounds), start_ix, reps);
+                Self::update_patt(result, Flat::RepetitionEnd(off, *bounds), end_ix, next_reps);
             }
             ElementCore::Alternative(p1, p2) => {
                 let left_ix = result.len();
                 Self::single_patt(result, Flat::AlternativeLeft(0), reps);
-                Self::pattern_patts(result, p1, reps, rep_incr);
+                Self::pattern_patts(result, p1, reps, flags);
                 let right_ix = result.len();
                 Self::single_patt(result, Flat::AlternativeRight(0), reps);
-                Self::pattern_patts(result, p2, reps, rep_incr);
+                Self::pattern_patts(result, p2, reps, flags);
                 let next_ix = result.len();
 
                 let left_off = right_ix - left_ix;
@@ -105,18 +149,42 @@ impl FlatPattern {
     }
 }
 
+struct Flags {
+    rep_incr: usize,
+    tokens: Tokens,
+    ignore_whitespace: bool,
+}
+
 /// An individual element in [`FlatPattern`].
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub enum Flat {
     /// Matches a specific character.
-    ///
-    /// Although this API implies this crate operates on unicode characters, the current code
-    /// sometimes naively converts bytes to characters, assuming ASCII.
     Lit(char),
+    /// Matches a specific character, ignoring case.
+    ///
+    /// We do not combine these into tokens, so they always match one character at a time.
+    CaselessLit(char),
+    /// Matches a specific sequence of characters, all inside the same text token.
+    ///
+    /// We only use this for pattern tokens with more than one character, and use
+    /// [`Lit`](Flat::Lit) for the rest.
+    Token(Vec<char>),
+    /// Matches any run of whitespace characters.
+    ///
+    /// This stores the original run of pattern whitespace, which we report if it is skipped.
+    Whitespace(Vec<char>),
     /// Matches a class of characters, e.g. `.` or `[a-z]`.
     Class(Class),
-    GroupStart,
-    GroupEnd,
+    /// Asserts something about the surrounding text, e.g. `^` or `\b`, without matching it.
+    Look(Look),
+    /// Starts a capture group.
+    ///
+    /// This stores the index and optional name of the group.
+    GroupStart(Group),
+    /// Ends a capture group.
+    ///
+    /// This stores the index of the group, as numbered by the original regex.
+    GroupEnd(usize),
     /// Starts the first branch of an alternation.
     ///
     /// This stores the offset between this item and the corresponding
@@ -129,12 +197,12 @@ pub enum Flat {
     /// Starts a repetition.
     ///
     /// This stores the offset between this item and the corresponding future
-    /// [`RepetitionEnd`](Flat::RepetitionEnd) item.
-    RepetitionStart(usize),
+    /// [`RepetitionEnd`](Flat::RepetitionEnd) item, and the bounds of the repetition.
+    RepetitionStart(usize, Bounds),
     /// Ends a repetition.
     ///
     /// This stores the offset between this item and the corresponding past
-    /// [`RepetitionStart`](Flat::RepetitionStart) item.
-    RepetitionEnd(usize),
+    /// [`RepetitionStart`](Flat::RepetitionStart) item, and the bounds of the repetition.
+    RepetitionEnd(usize, Bounds),
 }
 
@@ -6,31 +6,460 @@
 //! features we support and how well the "closest match" works in practice.
 
 use regex_syntax::hir;
+use std::fmt;
+use std::ops::Range;
+use std::sync::Arc;
 
 pub mod regex_pattern;
 pub mod table_solution;
 pub mod diff_output;
+pub mod capture_output;
 pub mod flat_pattern;
 pub mod error;
 
 use regex_pattern::parse_pattern;
-use table_solution::solve;
+use table_solution::{flatten, solve_flat};
+use table_solution::alternatives::{solve_all_flat, solve_k_best_flat};
 use diff_output::DiffOutput;
+use capture_output::Captures;
 use error::Error;
+use flat_pattern::FlatPattern;
 
-pub fn fuzzy_match(pattern_regex: String, text_str: String) -> Result<DiffOutput, Error> {
-    let pattern = parse_pattern(&pattern_regex)?;
-    let text = Atoms { atoms: text_str.chars().collect() };
-    let pattern_core = pattern.desugar();
-    let solution = solve(&pattern_core, &text)?;
-    let output = DiffOutput::new(&solution.score, &solution.trace);
-    return Ok(output);
+pub fn fuzzy_match(pattern_regex: String, text_str: String, options: &Options) -> Result<Output, Error> {
+    let compiled = CompiledPattern::new(&pattern_regex, options)?;
+    return compiled.match_text(&text_str);
+}
+
+/// A pattern we parse, desugar and flatten once, so we can match it against many texts.
+///
+/// Each match uses the options we compile the pattern with. A compiled pattern is `Send` and
+/// `Sync`, so several threads can share one.
+pub struct CompiledPattern {
+    pattern: Pattern<Element>,
+    core: Arc<Pattern<ElementCore>>,
+    flat: Arc<FlatPattern>,
+    options: Options,
+}
+
+impl CompiledPattern {
+    pub fn new(pattern_regex: &str, options: &Options) -> Result<Self, Error> {
+        let pattern = parse_pattern(pattern_regex, options.case_insensitive)?;
+        let core = Arc::new(pattern.desugar());
+        let flat = Arc::new(flatten(&core, options.tokens, options.ignore_whitespace));
+        Ok(CompiledPattern { pattern, core, flat, options: options.clone() })
+    }
+
+    /// Finds the optimal match for this text, like [`fuzzy_match`].
+    pub fn match_text(&self, text_str: &str) -> Result<Output, Error> {
+        let text = Atoms::new(text_str, self.options.tokens);
+        let solution = solve_flat(&self.flat, &text, &self.options)?;
+        Ok(Output::new(&self.core, solution, 0))
+    }
+
+    /// Finds every match which is as good as the optimal one, like [`fuzzy_match_all`].
+    pub fn match_all(&self, text_str: &str, limit: usize) -> Result<Vec<Output>, Error> {
+        let text = Atoms::new(text_str, self.options.tokens);
+        let solutions = solve_all_flat(&self.flat, &text, &self.options, limit)?;
+        Ok(solutions.into_iter().map(|solution| Output::new(&self.core, solution, 0)).collect())
+    }
+
+    /// Finds the `k` best distinct matches, like [`fuzzy_match_k_best`].
+    pub fn match_k_best(&self, text_str: &str, k: usize) -> Result<Vec<Output>, Error> {
+        let text = Atoms::new(text_str, self.options.tokens);
+        let solutions = solve_k_best_flat(&self.flat, &text, &self.options, k)?;
+        Ok(solutions.into_iter().map(|solution| Output::new(&self.core, solution, 0)).collect())
+    }
+
+    /// Finds every non-overlapping match in the text, like [`fuzzy_find_iter`].
+    pub fn find_iter(&self, text_str: &str, max_cost: usize) -> FindIter {
+        let text = Atoms::new(text_str, self.options.tokens);
+        let pending = vec![Pending::Window(0..text.atoms.len())];
+        let max_cost = self.options.max_cost.map_or(max_cost, |option_max| option_max.min(max_cost));
+        FindIter {
+            core: self.core.clone(),
+            flat: self.flat.clone(),
+            text,
+            options: Options { search: true, max_cost: Some(max_cost), ..self.options.clone() },
+            max_cost,
+            pending,
+        }
+    }
+
+    /// Returns the pattern as we parsed it.
+    pub fn pattern(&self) -> &Pattern<Element> {
+        &self.pattern
+    }
+
+    /// Returns the pattern after we desugared it, per [`Pattern::desugar`].
+    pub fn core(&self) -> &Pattern<ElementCore> {
+        &self.core
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+}
+
+/// Finds every match which is as good as the optimal one, up to `limit` of them.
+///
+/// When several matches have the same score, [`fuzzy_match`] only returns the first one it finds,
+/// which is also the first one here. This needs the table solver, without a band, and returns
+/// [`Error::SeveralMatchesNeedTable`] for any other [`Options::solver`] or [`Options::band`].
+pub fn fuzzy_match_all(pattern_regex: String, text_str: String, options: &Options, limit: usize) -> Result<Vec<Output>, Error> {
+    let compiled = CompiledPattern::new(&pattern_regex, options)?;
+    compiled.match_all(&text_str, limit)
+}
+
+/// Finds the `k` best distinct matches, in order of increasing score, leaving out any which cost
+/// more than [`Options::max_cost`].
+///
+/// Like [`fuzzy_match_all`], this needs the table solver, without a band.
+pub fn fuzzy_match_k_best(pattern_regex: String, text_str: String, options: &Options, k: usize) -> Result<Vec<Output>, Error> {
+    let compiled = CompiledPattern::new(&pattern_regex, options)?;
+    compiled.match_k_best(&text_str, k)
+}
+
+/// Finds every non-overlapping part of the text which matches the pattern at no more than
+/// `max_cost`, similar to regex `find_iter`.
+///
+/// We find the best match in the text, per [`Options::search`], and then look for more matches in
+/// the text before and after it. The iterator still returns the matches in text order, each with
+/// its own diff and captures. We do not report empty matches.
+pub fn fuzzy_find_iter(pattern_regex: String, text_str: String, options: &Options, max_cost: usize) -> Result<FindIter, Error> {
+    let compiled = CompiledPattern::new(&pattern_regex, options)?;
+    Ok(compiled.find_iter(&text_str, max_cost))
+}
+
+/// The matches that [`fuzzy_find_iter`] and [`CompiledPattern::find_iter`] find.
+pub struct FindIter {
+    core: Arc<Pattern<ElementCore>>,
+    flat: Arc<FlatPattern>,
+    text: Atoms,
+    options: Options,
+    max_cost: usize,
+    /// The windows of text we still have to search, and the matches we found between them, with
+    /// the earliest one last.
+    pending: Vec<Pending>,
+}
+
+enum Pending {
+    Window(Range<usize>),
+    Found(Output),
+}
+
+impl FindIter {
+    /// Finds the best match in this window of the text, if it is good enough.
+    fn best_match(&self, window: Range<usize>) -> Result<Option<Output>, Error> {
+        let text = self.text.window(window.clone());
+        let solution = match solve_flat(&self.flat, &text, &self.options) {
+            Err(Error::NoPossibleMatch | Error::NoMatchWithinMaxCost(_)) => return Ok(None),
+            result => result?,
+        };
+        if solution.score > self.max_cost || solution.range.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Output::new(&self.core, solution, window.start)))
+    }
+}
+
+impl Iterator for FindIter {
+    type Item = Result<Output, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(pending) = self.pending.pop() {
+            match pending {
+                Pending::Found(output) => return Some(Ok(output)),
+                Pending::Window(window) if window.is_empty() => {}
+                Pending::Window(window) => match self.best_match(window.clone()) {
+                    Ok(Some(output)) => {
+                        self.pending.push(Pending::Window(output.range.end..window.end));
+                        let before = window.start..output.range.start;
+                        self.pending.push(Pending::Found(output));
+                        self.pending.push(Pending::Window(before));
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        self.pending.clear();
+                        return Some(Err(err));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// The different views of the optimal match that [`fuzzy_match`] provides.
+pub struct Output {
+    pub score: usize,
+    /// The character indices of the matched text, which is the entire text unless
+    /// [`Options::search`] is set.
+    pub range: Range<usize>,
+    pub diff: DiffOutput,
+    pub captures: Captures,
+}
+
+impl Output {
+    /// Creates the output for a solution which starts at this index in the text.
+    fn new(pattern: &Pattern<ElementCore>, solution: Solution, offset: usize) -> Self {
+        let diff = DiffOutput::new(&solution.score, &solution.trace);
+        let range = solution.range.start + offset..solution.range.end + offset;
+        let captures = Captures::new_at(&solution.trace, &pattern.groups(), range.start);
+        Output { score: solution.score, range, diff, captures }
+    }
+}
+
+/// Configures how [`fuzzy_match`] finds the optimal match.
+#[derive(Eq, PartialEq, Clone, Debug, Default)]
+pub struct Options {
+    pub costs: Costs,
+    pub limits: Limits,
+    pub solver: Solver,
+    pub tokens: Tokens,
+    /// Matches any run of pattern whitespace against any run of text whitespace at no cost.
+    ///
+    /// This is useful when the pattern wraps lines differently to the text. The diff and captures
+    /// still show the original text whitespace.
+    ///
+    /// A run of pattern whitespace is a sequence of whitespace literals inside the same capture
+    /// group, so whitespace on either side of a group boundary, e.g. `(a )  b`, forms two separate
+    /// runs. The first run matches the text whitespace, and the second one matches no text right
+    /// after it. Whitespace classes, e.g. `\s` or `[ \t]`, are not runs, and still match one
+    /// character at a time.
+    pub ignore_whitespace: bool,
+    /// Ignores case for the entire pattern, as if it started with `(?i)`.
+    ///
+    /// Either way, a case-insensitive literal that only matches a text character if we ignore case
+    /// costs [`Costs::hit_case`].
+    pub case_insensitive: bool,
+    /// Finds the best match for the pattern anywhere inside the text, rather than matching the
+    /// entire text.
+    ///
+    /// Any text before and after the match is free to skip, and is left out of the diff.
+    pub search: bool,
+    /// The highest total cost we accept, or `None` if we accept any cost.
+    ///
+    /// We stop exploring a partial match as soon as it costs more than this, so hopeless matches
+    /// fail quickly with [`Error::NoMatchWithinMaxCost`].
+    pub max_cost: Option<usize>,
+    /// Only explores nodes whose text index is close to the text the pattern has matched so far,
+    /// or `None` to explore every node.
+    ///
+    /// This only applies to [`Solver::Table`], and is ignored when [`Options::search`] is set.
+    pub band: Option<Band>,
+    /// Chooses between matches with the same score.
+    pub tie_break: TieBreak,
+}
+
+/// Restricts the table solver to nodes near the diagonal, which is much faster when the text is
+/// close to the pattern.
+///
+/// A text index is inside the band when it is at most `width` characters before the fewest, or
+/// after the most, text characters that the pattern could match exactly up to that point. A match
+/// which leaves the band has to skip more than `width` pattern or text characters, so it costs at
+/// least that many skips.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct Band {
+    /// How far the text can drift from the pattern.
+    pub width: usize,
+    /// Doubles the width until the match is provably optimal, i.e. costs less than any match which
+    /// leaves the band, so we find the same match as an unrestricted solver.
+    ///
+    /// Otherwise, we find the optimal match inside the band, and fail with
+    /// [`Error::NoMatchWithinBand`] if there is none.
+    pub adaptive: bool,
+}
+
+/// How we choose between several matches with the same score, so diffs stay stable.
+///
+/// From each node, we take the first optimal step in a fixed order. By default, we hit the pattern
+/// before skipping anything, skip pattern before text, and take the left side of an alternative
+/// first, which makes hits as early as possible, e.g. `a[-a-]` rather than `[-a-]a` for the
+/// pattern `aa` and text `a`. Each field changes one part of that order, and every solver uses the
+/// same order, so they still find exactly the same match.
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub struct TieBreak {
+    /// Prefers hits before skips, so hits are as early as possible, or skips before hits, so hits
+    /// are as late as possible.
+    pub hits_first: bool,
+    /// Prefers skipping text before skipping pattern, e.g. `{+b+}a[-b-]` rather than `[-a-]b{+a+}`
+    /// for the pattern `ab` and text `ba`.
+    pub text_first: bool,
+    /// Prefers matches with fewer runs of consecutive skips, e.g. `[-abc-]b` rather than
+    /// `[-a-]b[-cb-]` for the pattern `abcb` and text `b`.
+    ///
+    /// This applies before the other preferences, and like [`Costs::gap_open`], the table needs
+    /// separate nodes inside and outside a run of skips.
+    pub group_skips: bool,
+    /// Prefers the left side of an alternative, or the right side.
+    pub left_first: bool,
+}
+
+impl Default for TieBreak {
+    fn default() -> Self {
+        TieBreak {
+            hits_first: true,
+            text_first: false,
+            group_skips: false,
+            left_first: true,
+        }
+    }
+}
+
+/// Which solver finds the optimal match. Every solver finds exactly the same match.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum Solver {
+    /// Stores a node for every pair of pattern and text indices. This is the fastest solver, but
+    /// the memory it needs grows with the product of the pattern and text lengths.
+    #[default]
+    Table,
+    /// Only stores nodes for a few text indices at a time, so the memory it needs grows with the
+    /// pattern length, times the logarithm of the text length. This is slower, as it calculates
+    /// most nodes several times, and it does not stop early when a match exceeds
+    /// [`Options::max_cost`].
+    LinearSpace,
+}
+
+/// How we split the pattern and text into tokens.
+///
+/// The optimal match only hits or skips whole tokens, so matching by word or line, rather than by
+/// character, produces diffs which replace whole words or lines. The exception is that pattern
+/// classes, e.g. `[a-z]`, still match a single character, which may be part of a larger token.
+///
+/// We only split consecutive pattern literals into tokens, e.g. `ab(cd)` has the tokens `ab` and
+/// `cd`, even if we are matching by line.
+#[derive(Eq, PartialEq, Clone, Copy, Debug, Default)]
+pub enum Tokens {
+    /// Every character is a token.
+    #[default]
+    Chars,
+    /// Every run of word characters, every run of whitespace, and every other character is a token.
+    Words,
+    /// Every line, including the newline at the end, is a token.
+    Lines,
+}
+
+impl Tokens {
+    /// Returns the index after the end of the token containing each character.
+    fn ends(&self, chars: &[char]) -> Vec<usize> {
+        let mut ends = vec![0; chars.len()];
+        let mut end = chars.len();
+        for i in (0..chars.len()).rev() {
+            if i + 1 < chars.len() && !self.joins(chars[i], chars[i + 1]) {
+                end = i + 1;
+            }
+            ends[i] = end;
+        }
+        ends
+    }
+
+    /// Checks if two adjacent characters belong to the same token.
+    fn joins(&self, before: char, after: char) -> bool {
+        match self {
+            Self::Chars => false,
+            Self::Words =>
+                (regex_syntax::is_word_character(before) && regex_syntax::is_word_character(after)) ||
+                (before.is_whitespace() && after.is_whitespace()),
+            Self::Lines => before != '\n',
+        }
+    }
+}
+
+/// The cost of each kind of step in a match. The optimal match minimises the total cost.
+///
+/// By default, every skipped pattern or text character costs 1, and every hit is free. Making
+/// skipped text cheaper than skipped pattern characters, for example, means fuzzy will prefer
+/// matching extra text over dropping pattern content.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Costs {
+    /// The cost of skipping a pattern literal, e.g. `a`.
+    pub skip_lit: usize,
+    /// The cost of skipping a pattern class, e.g. `[a-z]`.
+    pub skip_class: usize,
+    /// The cost of skipping a text character.
+    pub skip_text: usize,
+    /// The cost of matching a pattern literal against a text character.
+    pub hit_lit: usize,
+    /// The cost of matching a pattern class against a text character.
+    pub hit_class: usize,
+    /// The cost of matching a case-insensitive pattern literal against a text character with
+    /// different case, e.g. `(?i)a` against `A`.
+    ///
+    /// Costs are integers, so to make this a fraction of a skip, scale up the skip costs as well.
+    pub hit_case: usize,
+    /// The cost of violating a zero-width assertion, e.g. `^` or `\b`.
+    pub fail_look: usize,
+    /// The extra cost of starting a gap, i.e. a run of consecutive skipped pattern or text
+    /// characters.
+    ///
+    /// The skip costs above are the cost of extending the gap by one character. Setting this means
+    /// fuzzy prefers skipping one contiguous block over many scattered characters.
+    pub gap_open: usize,
+    /// The cost of replacing a pattern literal or class with a different text character, or `None`
+    /// if we can only skip both of them instead.
+    ///
+    /// We only replace single characters, so this does not apply to whole word or line tokens.
+    pub replace: Option<usize>,
+    /// The cost of matching two consecutive pattern literals or classes against the next two text
+    /// characters in reverse order, e.g. `the` against `teh`, or `None` if we cannot do this.
+    pub transpose: Option<usize>,
+    /// Characters which are expensive or impossible to skip, e.g. `"` or `\n` in structured files.
+    ///
+    /// These apply to pattern literals and text characters, but not pattern classes.
+    pub protected: Vec<char>,
+    /// The cost of skipping a protected character, or `None` if they cannot be skipped at all.
+    pub skip_protected: Option<usize>,
+}
+
+impl Default for Costs {
+    fn default() -> Self {
+        Costs {
+            skip_lit: 1,
+            skip_class: 1,
+            skip_text: 1,
+            hit_lit: 0,
+            hit_class: 0,
+            hit_case: 0,
+            fail_look: 1,
+            gap_open: 0,
+            replace: None,
+            transpose: None,
+            protected: vec![],
+            skip_protected: None,
+        }
+    }
+}
+
+/// Limits on the work we do to find the optimal match, so large inputs fail quickly rather than
+/// running for a very long time or running out of memory.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Limits {
+    /// The most steps the solver can take through its table before it gives up with
+    /// [`Error::ExceededMaxSteps`].
+    pub max_steps: usize,
+    /// The most nodes the solver can allocate for its table, or `None` if there is no limit.
+    ///
+    /// The solver needs a node for each pair of text and pattern indices, so the table grows with
+    /// the product of their lengths. We check this before allocating anything, and give up with
+    /// [`Error::ExceededMaxNodes`].
+    pub max_nodes: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_steps: 1000000000,
+            max_nodes: None,
+        }
+    }
 }
 
 #[derive(Eq, PartialEq, Debug)]
 pub struct Solution {
     pub score: usize,
     pub trace: Vec<Step<Match, char>>,
+    /// The character indices of the text covered by the trace.
+    pub range: Range<usize>,
 }
 
 #[derive(Eq, PartialEq, Clone, Debug)]
@@ -40,60 +469,117 @@ pub struct Pattern<E> {
 
 impl Pattern<Element> {
     pub fn desugar(&self) -> Pattern<ElementCore> {
+        self.desugar_with(false)
+    }
+
+    /// Desugars this pattern, but expands every counted repetition, e.g. `(a{2,3}b){1,4}` becomes
+    /// `aa(|a)b(aa(|a)b(aa(|a)b(aa(|a)b|)|)|)`.
+    ///
+    /// The solver counts iterations instead, even inside other counted repetitions, so we only
+    /// expand repetitions to check that both forms find a match with the same score. They can
+    /// find different matches with that score, as a pattern cannot tell if an iteration matched
+    /// nothing, so the expanded form may repeat it where the solver ends the repetition.
+    pub fn desugar_expanded(&self) -> Pattern<ElementCore> {
+        self.desugar_with(true)
+    }
+
+    fn desugar_with(&self, expand: bool) -> Pattern<ElementCore> {
         let mut elems = vec![];
         for elem in &self.elems {
             match elem {
                 Element::Match(m) => {
                     elems.push(ElementCore::Match(m.clone()));
                 }
-                Element::Capture(sugar) => {
-                    let inner = sugar.desugar();
-                    elems.push(ElementCore::Capture(inner));
+                Element::Look(look) => {
+                    elems.push(ElementCore::Look(*look));
                 }
-                Element::Repetition(Repetition { maximum: None, minimum, inner: sugar }) => {
-                    let inner = sugar.desugar();
-                    for _ in 0..*minimum {
-                        elems.extend(inner.elems.iter().cloned());
-                    }
-                    elems.push(ElementCore::Repetition(inner));
+                Element::Capture(group, sugar) => {
+                    let inner = sugar.desugar_with(expand);
+                    elems.push(ElementCore::Capture(group.clone(), inner));
                 }
-                Element::Repetition(Repetition { maximum: Some(maximum), minimum, inner: sugar }) => {
-                    // We desugar a repetition with a maximum bound as a massive alternative branch
-                    // TODO surely there be a better desugared output
-                    // TODO surely there must also be a better algorithm to build the output
-
-                    let inner = sugar.desugar();
-                    for _ in 0..*minimum {
-                        elems.extend(inner.elems.iter().cloned());
-                    }
-
-                    let empty = Pattern { elems: vec![] };
-                    let mut bounded_loop = empty.clone();
-                    for _ in *minimum..*maximum {
-                        let mut at_least_one_elems = vec![];
-                        at_least_one_elems.extend(inner.elems.iter().cloned());
-                        at_least_one_elems.extend(bounded_loop.elems.iter().cloned());
-
-                        let at_least_one = Pattern { elems: at_least_one_elems };
-                        bounded_loop = Pattern { elems: vec![ElementCore::Alternative(empty.clone(), at_least_one)] };
+                Element::Repetition(Repetition { minimum, maximum, greedy, inner: sugar }) => {
+                    let bounds = Bounds { minimum: *minimum, maximum: *maximum, greedy: *greedy };
+                    let inner = sugar.desugar_with(expand);
+                    if expand && bounds.is_counted() {
+                        Self::expand(&mut elems, bounds, inner);
+                    } else {
+                        elems.push(ElementCore::Repetition(bounds, inner));
                     }
-                    elems.extend(bounded_loop.elems.into_iter())
                 }
                 Element::Alternative(sugar1, sugar2) => {
-                    let inner1 = sugar1.desugar();
-                    let inner2 = sugar2.desugar();
+                    let inner1 = sugar1.desugar_with(expand);
+                    let inner2 = sugar2.desugar_with(expand);
                     elems.push(ElementCore::Alternative(inner1, inner2));
                 }
             }
         }
         Pattern { elems }
     }
+
+    /// Expands a repetition into a copy of the inner pattern for each iteration it must match,
+    /// followed by a nested alternative for each optional iteration, or an unbounded repetition.
+    ///
+    /// A greedy repetition tries another iteration on the left side of each alternative, while a
+    /// lazy repetition tries to stop there.
+    fn expand(elems: &mut Vec<ElementCore>, bounds: Bounds, inner: Pattern<ElementCore>) {
+        for _ in 0..bounds.minimum {
+            elems.extend(inner.elems.iter().cloned());
+        }
+        match bounds.maximum {
+            None => elems.push(ElementCore::Repetition(Bounds { greedy: bounds.greedy, ..Bounds::default() }, inner)),
+            Some(maximum) => {
+                let empty = Pattern { elems: vec![] };
+                let mut bounded_loop = empty.clone();
+                for _ in bounds.minimum..maximum {
+                    let mut at_least_one_elems = vec![];
+                    at_least_one_elems.extend(inner.elems.iter().cloned());
+                    at_least_one_elems.extend(bounded_loop.elems.iter().cloned());
+
+                    let at_least_one = Pattern { elems: at_least_one_elems };
+                    let alternative = if bounds.greedy {
+                        ElementCore::Alternative(at_least_one, empty.clone())
+                    } else {
+                        ElementCore::Alternative(empty.clone(), at_least_one)
+                    };
+                    bounded_loop = Pattern { elems: vec![alternative] };
+                }
+                elems.extend(bounded_loop.elems);
+            }
+        }
+    }
+}
+
+impl Pattern<ElementCore> {
+    /// Returns every capture group in this pattern, in the order they start.
+    pub fn groups(&self) -> Vec<Group> {
+        let mut groups = vec![];
+        self.collect_groups(&mut groups);
+        groups
+    }
+
+    fn collect_groups(&self, groups: &mut Vec<Group>) {
+        for elem in &self.elems {
+            match elem {
+                ElementCore::Match(_) | ElementCore::Look(_) => {}
+                ElementCore::Capture(group, inner) => {
+                    groups.push(group.clone());
+                    inner.collect_groups(groups);
+                }
+                ElementCore::Repetition(_, inner) => inner.collect_groups(groups),
+                ElementCore::Alternative(inner1, inner2) => {
+                    inner1.collect_groups(groups);
+                    inner2.collect_groups(groups);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub enum Element {
     Match(Match),
-    Capture(Pattern<Element>),
+    Look(Look),
+    Capture(Group, Pattern<Element>),
     Repetition(Repetition),
     Alternative(Pattern<Element>, Pattern<Element>),
 }
@@ -101,28 +587,104 @@ pub enum Element {
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub enum ElementCore {
     Match(Match),
-    Capture(Pattern<ElementCore>),
-    Repetition(Pattern<ElementCore>),
+    Look(Look),
+    Capture(Group, Pattern<ElementCore>),
+    /// Repeats the inner pattern within these bounds.
+    ///
+    /// The solver counts iterations rather than copying the inner pattern for each one, so large
+    /// bounds, e.g. `[0-9]{1,1000}`, do not make the pattern any larger. The table still needs a
+    /// node for each count we track, so a bound only saves memory when it is larger than the text,
+    /// and we can stop counting at the minimum.
+    Repetition(Bounds, Pattern<ElementCore>),
     Alternative(Pattern<ElementCore>, Pattern<ElementCore>),
 }
 
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub enum Match {
     Lit(char),
+    /// Matches a specific character, ignoring case, e.g. `(?i)a`.
+    ///
+    /// This stores the character as written in the pattern, so we can recognise case-only changes.
+    CaselessLit(char),
     Class(Class),
 }
 
+/// Identifies a capture group, e.g. `(a)` or `(?P<name>a)`.
+#[derive(Eq, PartialEq, Clone, Debug)]
+pub struct Group {
+    /// The index of this group, as numbered by the original regex.
+    pub index: usize,
+    pub name: Option<String>,
+}
+
+/// How many times a repetition can match its inner pattern. The default is `*`, i.e. any number.
+#[derive(Eq, PartialEq, Clone, Copy, Hash, Debug)]
+pub struct Bounds {
+    pub minimum: usize,
+    /// The most iterations, or `None` if there is no limit.
+    pub maximum: Option<usize>,
+    /// Prefers more iterations over fewer when both match equally well, like `*` rather than `*?`.
+    ///
+    /// This only chooses between matches with the same score, e.g. `"(.*?)"` captures the
+    /// shortest quoted text it can without costing more.
+    pub greedy: bool,
+}
+
+impl Bounds {
+    /// Checks if the solver needs to count iterations to respect these bounds, i.e. if they are
+    /// anything other than `*`.
+    pub fn is_counted(&self) -> bool {
+        self.minimum > 0 || self.maximum.is_some()
+    }
+}
+
+impl Default for Bounds {
+    fn default() -> Self {
+        Bounds { minimum: 0, maximum: None, greedy: true }
+    }
+}
+
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub struct Repetition {
     minimum: usize,
     maximum: Option<usize>,
+    greedy: bool,
     inner: Pattern<Element>,
 }
 
-// using the term atom as we might eventually match words/lines/etc.
+/// The text we are matching, split into [`Tokens`].
+///
+/// We still index the text by character, so we can report the characters in each hit or skip.
 #[derive(Eq, PartialEq, Clone, Debug)]
 pub struct Atoms {
     atoms: Vec<char>,
+    /// The index after the end of the token containing each character.
+    ends: Vec<usize>,
+    tokens: Tokens,
+    /// The character just before this text, if it is a window into a larger text.
+    before: Option<char>,
+    /// The character just after this text, if it is a window into a larger text.
+    after: Option<char>,
+}
+
+impl Atoms {
+    pub fn new(text: &str, tokens: Tokens) -> Self {
+        let atoms: Vec<char> = text.chars().collect();
+        let ends = tokens.ends(&atoms);
+        Atoms { atoms, ends, tokens, before: None, after: None }
+    }
+
+    /// Returns the characters in this range as a text of its own.
+    ///
+    /// We cut any token that crosses the edge of the window, but remember the characters either
+    /// side of it, so assertions like `\b` still see them.
+    fn window(&self, range: Range<usize>) -> Self {
+        let atoms = self.atoms[range.clone()].to_vec();
+        let ends = self.ends[range.clone()].iter().map(|end| end.min(&range.end) - range.start).collect();
+        let before = range.start.checked_sub(1).map_or(self.before, |i| Some(self.atoms[i]));
+        let after = self.atoms.get(range.end).copied().or(self.after);
+        Atoms { atoms, ends, tokens: self.tokens, before, after }
+    }
 }
 
 /// Represents a class of characters, e.g. `.` or `[a-z]`.
@@ -147,38 +709,107 @@ impl Class {
             hir::Class::Unicode(ranges) =>
                 ranges.iter().any(|range| range.start() <= c && c <= range.end()),
             hir::Class::Bytes(ranges) =>
-                // TODO As in other places in the code, for now, we are treating the u8 vs char
-                // distinctiom by naively assuming all our text is ASCII
-                ranges.iter().any(|range| {
-                    let start = range.start() as char;
-                    let end = range.end() as char;
-                    start <= c && c <= end
+                // a byte class can only match characters that are encoded as a single byte
+                c.is_ascii() && ranges.iter().any(|range| {
+                    let b = c as u8;
+                    range.start() <= b && b <= range.end()
                 }),
         }
     }
 }
 
-/// An individual element in [`Solution::trace`].
+/// Represents a zero-width assertion, e.g. `^`, `$` or `\b`.
+///
+/// Like [`Class`], we currently implement this by re-using
+/// [regex_syntax's `Look`](https://docs.rs/regex-syntax/latest/regex_syntax/hir/enum.Look.html).
 #[derive(Eq, PartialEq, Clone, Copy, Debug)]
-pub enum Step<P, T> {
-    Hit(P, T),
-    SkipPattern(P),
-    SkipText(T),
-    StartCapture,
-    StopCapture,
+pub struct Look {
+    hir_look: hir::Look,
 }
 
-impl <P, T> Step<P, T> {
-    fn map<Q, U, FQ: Fn(&P) -> Q, FU: Fn(&T) -> U>(&self, fq: FQ, fu: FU) -> Step<Q, U> {
-        match self {
-            Self::Hit(p, t) => Step::Hit(fq(p), fu(t)),
-            Self::SkipPattern(p) => Step::SkipPattern(fq(p)),
-            Self::SkipText(t) => Step::SkipText(fu(t)),
-            Self::StartCapture => Step::StartCapture,
-            Self::StopCapture => Step::StopCapture,
+impl From<hir::Look> for Look {
+    fn from(hir_look: hir::Look) -> Look {
+        Look { hir_look }
+    }
+}
+
+impl Look {
+    /// Checks the assertion at the position between the `before` and `after` characters.
+    ///
+    /// `before` is `None` at the start of the text, and `after` is `None` at the end of the text.
+    pub fn matches(&self, before: Option<char>, after: Option<char>) -> bool {
+        match self.hir_look {
+            hir::Look::Start => before.is_none(),
+            hir::Look::End => after.is_none(),
+            hir::Look::StartLF => matches!(before, None | Some('\n')),
+            hir::Look::EndLF => matches!(after, None | Some('\n')),
+            hir::Look::StartCRLF => match before {
+                None | Some('\n') => true,
+                Some('\r') => after != Some('\n'),
+                Some(_) => false,
+            }
+            hir::Look::EndCRLF => match after {
+                None | Some('\r') => true,
+                Some('\n') => before != Some('\r'),
+                Some(_) => false,
+            }
+            hir::Look::WordAscii =>
+                Self::is_word(before, Self::is_ascii_word) != Self::is_word(after, Self::is_ascii_word),
+            hir::Look::WordAsciiNegate =>
+                Self::is_word(before, Self::is_ascii_word) == Self::is_word(after, Self::is_ascii_word),
+            hir::Look::WordUnicode =>
+                Self::is_word(before, regex_syntax::is_word_character) != Self::is_word(after, regex_syntax::is_word_character),
+            hir::Look::WordUnicodeNegate =>
+                Self::is_word(before, regex_syntax::is_word_character) == Self::is_word(after, regex_syntax::is_word_character),
         }
     }
 
+    fn is_word(c: Option<char>, is_word_char: fn(char) -> bool) -> bool {
+        c.is_some_and(is_word_char)
+    }
+
+    fn is_ascii_word(c: char) -> bool {
+        c.is_ascii_alphanumeric() || c == '_'
+    }
+}
+
+/// Displays the assertion in regex syntax, e.g. `^` or `\b`.
+impl fmt::Display for Look {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let syntax = match self.hir_look {
+            hir::Look::Start => "^",
+            hir::Look::End => "$",
+            hir::Look::StartLF => "(?m:^)",
+            hir::Look::EndLF => "(?m:$)",
+            hir::Look::StartCRLF => "(?mR:^)",
+            hir::Look::EndCRLF => "(?mR:$)",
+            hir::Look::WordAscii => "(?-u:\\b)",
+            hir::Look::WordAsciiNegate => "(?-u:\\B)",
+            hir::Look::WordUnicode => "\\b",
+            hir::Look::WordUnicodeNegate => "\\B",
+        };
+        write!(f, "{}", syntax)
+    }
+}
+
+/// An individual element in [`Solution::trace`].
+#[derive(Eq, PartialEq, Clone, Copy, Debug)]
+pub enum Step<P, T> {
+    Hit(P, T),
+    /// Replaces a pattern literal or class with a text character it does not match.
+    Replace(P, T),
+    /// Matches two consecutive pattern elements against two text characters in reverse order.
+    ///
+    /// This stores the pattern elements in pattern order, followed by the characters in text order,
+    /// so the first pattern element matches the second text character and vice versa.
+    Transpose(P, P, T, T),
+    SkipPattern(P),
+    SkipText(T),
+    /// Violates a zero-width assertion, e.g. `^` or `\b`, which does not hold at this point in the
+    /// text.
+    FailLook(Look),
+    StartCapture(usize),
+    StopCapture(usize),
 }
 
 #[cfg(test)]
@@ -225,6 +856,45 @@ pub mod test_cases {
             }
         }
 
+        pub fn match_empty_capture() -> Self {
+            Self {
+                pattern: pattern(vec![capture(1, vec![])]),
+                text: text(""),
+                score: 0,
+                trace: vec![
+                    Step::StartCapture(1),
+                    Step::StopCapture(1),
+                ],
+            }
+        }
+
+        pub fn match_empty_alternative() -> Self {
+            Self {
+                pattern: pattern(vec![lit('a'), capture(1, vec![alt(lits("b"), vec![])]), lit('c')]),
+                text: text("ac"),
+                score: 0,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::StartCapture(1),
+                    Step::StopCapture(1),
+                    Step::Hit(Match::Lit('c'), 'c'),
+                ],
+            }
+        }
+
+        pub fn fail_empty_repetition() -> Self {
+            Self {
+                pattern: pattern(vec![rep(vec![]), rep(vec![capture(1, vec![])])]),
+                text: text("a"),
+                score: 1,
+                trace: vec![
+                    Step::StartCapture(1),
+                    Step::StopCapture(1),
+                    Step::SkipText('a'),
+                ],
+            }
+        }
+
         pub fn match_lit_1() -> Self {
             Self {
                 pattern: pattern(lits("a")),
@@ -282,7 +952,7 @@ pub mod test_cases {
                     Step::SkipText('z'),
                     Step::Hit(Match::Lit('a'), 'a'),
                     Step::Hit(Match::Lit('b'), 'b'),
-                    // TODO handle valid possibility that the order of next three steps is changed
+                    // solve_all also finds the other orders of the next three steps
                     Step::SkipPattern(Match::Lit('c')),
                     Step::SkipPattern(Match::Lit('d')),
                     Step::SkipText('k'),
@@ -330,13 +1000,67 @@ pub mod test_cases {
                 text: text("a"),
                 score: 2,
                 trace: vec![
-                    // TODO handle valid possibility that the order of next two steps is reversed
+                    // solve_all also finds these two steps in reverse order
                     Step::SkipPattern(patt_class("[^a]")),
                     Step::SkipText('a'),
                 ],
             }
         }
 
+        pub fn match_class_4() -> Self {
+            Self {
+                pattern: pattern(vec![class("[à-ÿ]"), class("(?-u:[a-z])")]),
+                text: text("éa"),
+                score: 0,
+                trace: vec![
+                    Step::Hit(patt_class("[à-ÿ]"), 'é'),
+                    Step::Hit(patt_class("(?-u:[a-z])"), 'a'),
+                ],
+            }
+        }
+
+        pub fn fail_class_2() -> Self {
+            Self {
+                pattern: pattern(vec![class(r"(?-u:[\x00-\x7F])")]),
+                text: text("é"),
+                score: 2,
+                trace: vec![
+                    Step::SkipPattern(patt_class(r"(?-u:[\x00-\x7F])")),
+                    Step::SkipText('é'),
+                ],
+            }
+        }
+
+        pub fn match_unicode_1() -> Self {
+            Self {
+                pattern: pattern(lits("© José")),
+                text: text("© José"),
+                score: 0,
+                trace: vec![
+                    Step::Hit(Match::Lit('©'), '©'),
+                    Step::Hit(Match::Lit(' '), ' '),
+                    Step::Hit(Match::Lit('J'), 'J'),
+                    Step::Hit(Match::Lit('o'), 'o'),
+                    Step::Hit(Match::Lit('s'), 's'),
+                    Step::Hit(Match::Lit('é'), 'é'),
+                ],
+            }
+        }
+
+        pub fn fail_unicode_1() -> Self {
+            Self {
+                pattern: pattern(lits("日本語")),
+                text: text("日本人"),
+                score: 2,
+                trace: vec![
+                    Step::Hit(Match::Lit('日'), '日'),
+                    Step::Hit(Match::Lit('本'), '本'),
+                    Step::SkipPattern(Match::Lit('語')),
+                    Step::SkipText('人'),
+                ],
+            }
+        }
+
         pub fn match_alternative_1() -> Self {
             Self {
                 pattern: pattern(vec![alt(lits("ab"), lits("cd"))]),
@@ -492,25 +1216,647 @@ pub mod test_cases {
                 ],
             }
         }
-    }
-
-    pub fn patt_class(regex: &str) -> Match {
-        let wildcard_class = match regex_syntax::parse(regex).unwrap().into_kind() {
-            HirKind::Class(c) => c,
-            unsupported => panic!("Unexpected regex_syntax for class: {:?}", unsupported),
-        };
-
-        Match::Class(Class::from(wildcard_class))
-    }
-
-    pub fn pattern(elems: Vec<Element>) -> Pattern<Element> {
-        Pattern { elems }
-    }
+        pub fn match_capture_1() -> Self {
+            Self {
+                pattern: pattern(vec![lit('a'), capture(1, lits("b")), lit('c')]),
+                text: text("abc"),
+                score: 0,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::StartCapture(1),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                    Step::StopCapture(1),
+                    Step::Hit(Match::Lit('c'), 'c'),
+                ],
+            }
+        }
 
-    pub fn text(text: &str) -> Atoms {
-        let atoms = text.chars().collect();
-        Atoms { atoms }
-    }
+        pub fn match_capture_2() -> Self {
+            Self {
+                pattern: pattern(vec![alt(vec![capture(1, lits("ab"))], vec![capture(2, lits("cd"))])]),
+                text: text("cd"),
+                score: 0,
+                trace: vec![
+                    Step::StartCapture(2),
+                    Step::Hit(Match::Lit('c'), 'c'),
+                    Step::Hit(Match::Lit('d'), 'd'),
+                    Step::StopCapture(2),
+                ],
+            }
+        }
+
+        pub fn match_capture_3() -> Self {
+            Self {
+                pattern: pattern(vec![rep(vec![capture(1, vec![lit('a'), capture(2, lits("b"))])])]),
+                text: text("abab"),
+                score: 0,
+                trace: vec![
+                    Step::StartCapture(1),
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::StartCapture(2),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                    Step::StopCapture(2),
+                    Step::StopCapture(1),
+                    Step::StartCapture(1),
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::StartCapture(2),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                    Step::StopCapture(2),
+                    Step::StopCapture(1),
+                ],
+            }
+        }
+
+        pub fn match_named_capture_1() -> Self {
+            Self {
+                pattern: pattern(vec![
+                    named_capture(1, "key", lits("ab")),
+                    lit('='),
+                    capture(2, lits("c")),
+                    named_capture(3, "value", lits("d")),
+                ]),
+                text: text("ab=cd"),
+                score: 0,
+                trace: vec![
+                    Step::StartCapture(1),
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                    Step::StopCapture(1),
+                    Step::Hit(Match::Lit('='), '='),
+                    Step::StartCapture(2),
+                    Step::Hit(Match::Lit('c'), 'c'),
+                    Step::StopCapture(2),
+                    Step::StartCapture(3),
+                    Step::Hit(Match::Lit('d'), 'd'),
+                    Step::StopCapture(3),
+                ],
+            }
+        }
+
+        pub fn fail_capture_1() -> Self {
+            Self {
+                pattern: pattern(vec![lit('a'), capture(1, lits("bc")), lit('d')]),
+                text: text("axbd"),
+                score: 2,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::StartCapture(1),
+                    Step::SkipText('x'),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                    Step::SkipPattern(Match::Lit('c')),
+                    Step::StopCapture(1),
+                    Step::Hit(Match::Lit('d'), 'd'),
+                ],
+            }
+        }
+        pub fn match_look_1() -> Self {
+            Self {
+                pattern: pattern(vec![look("^"), lit('a'), look("$")]),
+                text: text("a"),
+                score: 0,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                ],
+            }
+        }
+
+        pub fn match_look_2() -> Self {
+            Self {
+                pattern: pattern(vec![lit('a'), lit('\n'), look("(?m:^)"), lit('b'), look("(?m:$)")]),
+                text: text("a\nb"),
+                score: 0,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::Hit(Match::Lit('\n'), '\n'),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                ],
+            }
+        }
+
+        pub fn match_look_3() -> Self {
+            Self {
+                pattern: pattern(vec![look(r"\b"), lit('a'), look(r"\B"), lit('b'), look(r"\b"), lit(' ')]),
+                text: text("ab "),
+                score: 0,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                    Step::Hit(Match::Lit(' '), ' '),
+                ],
+            }
+        }
+
+        pub fn fail_look_1() -> Self {
+            Self {
+                pattern: pattern(vec![lit('a'), look("(?m:^)"), lit('b')]),
+                text: text("ab"),
+                score: 1,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::FailLook(patt_look("(?m:^)")),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                ],
+            }
+        }
+
+        pub fn fail_look_2() -> Self {
+            Self {
+                pattern: pattern(vec![rep(vec![class("[a-z ]")]), look("(?m:^)"), lit('k'), lit('e'), lit('y')]),
+                text: text("a key\nkey"),
+                score: 1,
+                trace: vec![
+                    Step::Hit(patt_class("[a-z ]"), 'a'),
+                    Step::Hit(patt_class("[a-z ]"), ' '),
+                    Step::Hit(patt_class("[a-z ]"), 'k'),
+                    Step::Hit(patt_class("[a-z ]"), 'e'),
+                    Step::Hit(patt_class("[a-z ]"), 'y'),
+                    Step::SkipText('\n'),
+                    Step::Hit(Match::Lit('k'), 'k'),
+                    Step::Hit(Match::Lit('e'), 'e'),
+                    Step::Hit(Match::Lit('y'), 'y'),
+                ],
+            }
+        }
+        pub fn match_words_1() -> Self {
+            Self {
+                pattern: pattern(lits("hi you")),
+                text: words("hi you"),
+                score: 0,
+                trace: vec![
+                    Step::Hit(Match::Lit('h'), 'h'),
+                    Step::Hit(Match::Lit('i'), 'i'),
+                    Step::Hit(Match::Lit(' '), ' '),
+                    Step::Hit(Match::Lit('y'), 'y'),
+                    Step::Hit(Match::Lit('o'), 'o'),
+                    Step::Hit(Match::Lit('u'), 'u'),
+                ],
+            }
+        }
+
+        pub fn fail_words_1() -> Self {
+            Self {
+                pattern: pattern(lits("hi you")),
+                text: words("hi yo"),
+                score: 2,
+                trace: vec![
+                    Step::Hit(Match::Lit('h'), 'h'),
+                    Step::Hit(Match::Lit('i'), 'i'),
+                    Step::Hit(Match::Lit(' '), ' '),
+                    Step::SkipPattern(Match::Lit('y')),
+                    Step::SkipPattern(Match::Lit('o')),
+                    Step::SkipPattern(Match::Lit('u')),
+                    Step::SkipText('y'),
+                    Step::SkipText('o'),
+                ],
+            }
+        }
+
+        pub fn fail_words_2() -> Self {
+            Self {
+                pattern: pattern(vec![lit('v'), lit(' '), rep_min(1, vec![class("[0-9]")])]),
+                text: words("v 12xy"),
+                score: 1,
+                trace: vec![
+                    Step::Hit(Match::Lit('v'), 'v'),
+                    Step::Hit(Match::Lit(' '), ' '),
+                    Step::Hit(patt_class("[0-9]"), '1'),
+                    Step::Hit(patt_class("[0-9]"), '2'),
+                    Step::SkipText('x'),
+                    Step::SkipText('y'),
+                ],
+            }
+        }
+
+        pub fn fail_lines_1() -> Self {
+            Self {
+                pattern: pattern(lits("a\nb\nc\n")),
+                text: lines("a\nx\nc\n"),
+                score: 2,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::Hit(Match::Lit('\n'), '\n'),
+                    Step::SkipPattern(Match::Lit('b')),
+                    Step::SkipPattern(Match::Lit('\n')),
+                    Step::SkipText('x'),
+                    Step::SkipText('\n'),
+                    Step::Hit(Match::Lit('c'), 'c'),
+                    Step::Hit(Match::Lit('\n'), '\n'),
+                ],
+            }
+        }
+
+        pub fn fail_lines_2() -> Self {
+            Self {
+                pattern: pattern(vec![capture(1, lits("ab\n"))]),
+                text: lines("ab\nc\nd"),
+                score: 2,
+                trace: vec![
+                    Step::StartCapture(1),
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                    Step::Hit(Match::Lit('\n'), '\n'),
+                    Step::StopCapture(1),
+                    Step::SkipText('c'),
+                    Step::SkipText('\n'),
+                    Step::SkipText('d'),
+                ],
+            }
+        }
+
+        pub fn match_caseless_1() -> Self {
+            Self {
+                pattern: pattern(vec![caseless('H'), caseless('i'), lit('!')]),
+                text: text("hi!"),
+                score: 0,
+                trace: vec![
+                    Step::Hit(Match::CaselessLit('H'), 'h'),
+                    Step::Hit(Match::CaselessLit('i'), 'i'),
+                    Step::Hit(Match::Lit('!'), '!'),
+                ],
+            }
+        }
+
+        pub fn fail_caseless_1() -> Self {
+            Self {
+                pattern: pattern(vec![caseless('a'), lit('b')]),
+                text: text("AB"),
+                score: 2,
+                trace: vec![
+                    Step::Hit(Match::CaselessLit('a'), 'A'),
+                    Step::SkipPattern(Match::Lit('b')),
+                    Step::SkipText('B'),
+                ],
+            }
+        }
+
+        // the following test cases expect non-default options
+
+        pub fn fail_transpose_1() -> Self {
+            Self {
+                pattern: pattern(lits("the")),
+                text: text("teh"),
+                score: 1,
+                trace: vec![
+                    Step::Hit(Match::Lit('t'), 't'),
+                    Step::Transpose(Match::Lit('h'), Match::Lit('e'), 'e', 'h'),
+                ],
+            }
+        }
+
+        pub fn fail_transpose_2() -> Self {
+            Self {
+                pattern: pattern(vec![lit('r'), lit('e'), lit('c'), lit('e'), class("[ij]"), lit('p'), lit('t')]),
+                text: text("reciept"),
+                score: 1,
+                trace: vec![
+                    Step::Hit(Match::Lit('r'), 'r'),
+                    Step::Hit(Match::Lit('e'), 'e'),
+                    Step::Hit(Match::Lit('c'), 'c'),
+                    Step::Transpose(Match::Lit('e'), patt_class("[ij]"), 'i', 'e'),
+                    Step::Hit(Match::Lit('p'), 'p'),
+                    Step::Hit(Match::Lit('t'), 't'),
+                ],
+            }
+        }
+
+        pub fn fail_transpose_3() -> Self {
+            Self {
+                pattern: pattern(vec![lit('a'), capture(1, lits("b"))]),
+                text: text("ba"),
+                score: 2,
+                trace: vec![
+                    Step::SkipPattern(Match::Lit('a')),
+                    Step::StartCapture(1),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                    Step::StopCapture(1),
+                    Step::SkipText('a'),
+                ],
+            }
+        }
+
+        pub fn fail_gap_1() -> Self {
+            Self {
+                pattern: pattern(lits("abc")),
+                text: text("aXbYcabc"),
+                score: 7,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::SkipText('X'),
+                    Step::SkipText('b'),
+                    Step::SkipText('Y'),
+                    Step::SkipText('c'),
+                    Step::SkipText('a'),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                    Step::Hit(Match::Lit('c'), 'c'),
+                ],
+            }
+        }
+
+        pub fn fail_gap_2() -> Self {
+            Self {
+                pattern: pattern(vec![lit('a'), capture(1, lits("bc")), lit('d')]),
+                text: text("ad"),
+                score: 4,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::StartCapture(1),
+                    Step::SkipPattern(Match::Lit('b')),
+                    Step::SkipPattern(Match::Lit('c')),
+                    Step::StopCapture(1),
+                    Step::Hit(Match::Lit('d'), 'd'),
+                ],
+            }
+        }
+
+        pub fn fail_gap_3() -> Self {
+            Self {
+                pattern: pattern(lits("ab")),
+                text: text("xy"),
+                score: 5,
+                trace: vec![
+                    Step::SkipPattern(Match::Lit('a')),
+                    Step::SkipPattern(Match::Lit('b')),
+                    Step::SkipText('x'),
+                    Step::SkipText('y'),
+                ],
+            }
+        }
+
+        pub fn match_search_1() -> Self {
+            Self {
+                pattern: pattern(lits("abc")),
+                text: text("xxabcyy"),
+                score: 0,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                    Step::Hit(Match::Lit('c'), 'c'),
+                ],
+            }
+        }
+
+        pub fn match_search_2() -> Self {
+            Self {
+                pattern: pattern(vec![capture(1, lits("ab"))]),
+                text: text("abzab"),
+                score: 0,
+                trace: vec![
+                    Step::StartCapture(1),
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                    Step::StopCapture(1),
+                ],
+            }
+        }
+
+        pub fn match_search_3() -> Self {
+            Self {
+                pattern: pattern(vec![]),
+                text: text("xx"),
+                score: 0,
+                trace: vec![],
+            }
+        }
+
+        pub fn fail_search_1() -> Self {
+            Self {
+                pattern: pattern(lits("hello")),
+                text: text("xx helo yy"),
+                score: 1,
+                trace: vec![
+                    Step::Hit(Match::Lit('h'), 'h'),
+                    Step::Hit(Match::Lit('e'), 'e'),
+                    Step::Hit(Match::Lit('l'), 'l'),
+                    Step::SkipPattern(Match::Lit('l')),
+                    Step::Hit(Match::Lit('o'), 'o'),
+                ],
+            }
+        }
+
+        pub fn fail_replace_1() -> Self {
+            Self {
+                pattern: pattern(lits("abc")),
+                text: text("axc"),
+                score: 1,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::Replace(Match::Lit('b'), 'x'),
+                    Step::Hit(Match::Lit('c'), 'c'),
+                ],
+            }
+        }
+
+        pub fn fail_replace_2() -> Self {
+            Self {
+                pattern: pattern(vec![class("[0-9]"), lit('b')]),
+                text: text("ab"),
+                score: 1,
+                trace: vec![
+                    Step::Replace(patt_class("[0-9]"), 'a'),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                ],
+            }
+        }
+
+        pub fn fail_replace_3() -> Self {
+            Self {
+                pattern: pattern(lits("ab")),
+                text: text("ba"),
+                score: 2,
+                trace: vec![
+                    Step::Replace(Match::Lit('a'), 'b'),
+                    Step::Replace(Match::Lit('b'), 'a'),
+                ],
+            }
+        }
+
+        pub fn fail_costs_hit_case() -> Self {
+            Self {
+                pattern: pattern(vec![caseless('a'), caseless('b'), caseless('c')]),
+                text: text("aBC"),
+                score: 2,
+                trace: vec![
+                    Step::Hit(Match::CaselessLit('a'), 'a'),
+                    Step::Hit(Match::CaselessLit('b'), 'B'),
+                    Step::Hit(Match::CaselessLit('c'), 'C'),
+                ],
+            }
+        }
+
+        pub fn match_whitespace_1() -> Self {
+            Self {
+                pattern: pattern(lits("a b")),
+                text: text("a\n\tb"),
+                score: 0,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::Hit(Match::Lit(' '), '\n'),
+                    Step::Hit(Match::Lit(' '), '\t'),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                ],
+            }
+        }
+
+        pub fn match_whitespace_2() -> Self {
+            Self {
+                pattern: pattern(lits("a \n b")),
+                text: text("a b"),
+                score: 0,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::Hit(Match::Lit(' '), ' '),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                ],
+            }
+        }
+
+        pub fn match_whitespace_3() -> Self {
+            Self {
+                pattern: pattern(vec![lit('a'), lit(' '), capture(1, lits(" b"))]),
+                text: words("a  \n b"),
+                score: 0,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::Hit(Match::Lit(' '), ' '),
+                    Step::Hit(Match::Lit(' '), ' '),
+                    Step::Hit(Match::Lit(' '), '\n'),
+                    Step::Hit(Match::Lit(' '), ' '),
+                    Step::StartCapture(1),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                    Step::StopCapture(1),
+                ],
+            }
+        }
+
+        pub fn match_whitespace_4() -> Self {
+            Self {
+                pattern: pattern(vec![capture(1, lits("a ")), lit(' ')]),
+                text: text("a\t"),
+                score: 0,
+                trace: vec![
+                    Step::StartCapture(1),
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::Hit(Match::Lit(' '), '\t'),
+                    Step::StopCapture(1),
+                ],
+            }
+        }
+
+        pub fn fail_whitespace_1() -> Self {
+            Self {
+                pattern: pattern(lits("a b")),
+                text: text("ab"),
+                score: 1,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::SkipPattern(Match::Lit(' ')),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                ],
+            }
+        }
+
+
+        pub fn match_costs_hit_class() -> Self {
+            Self {
+                pattern: pattern(vec![alt(vec![class("[a-z]")], lits("a"))]),
+                text: text("a"),
+                score: 0,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                ],
+            }
+        }
+
+        pub fn fail_costs_skip_class() -> Self {
+            Self {
+                pattern: pattern(vec![alt(lits("a"), vec![class("[0-9]")])]),
+                text: text(""),
+                score: 1,
+                trace: vec![
+                    Step::SkipPattern(patt_class("[0-9]")),
+                ],
+            }
+        }
+
+        pub fn fail_costs_skip_text() -> Self {
+            Self {
+                pattern: pattern(vec![lit('x'), alt(lits("abc"), vec![])]),
+                text: text("xa"),
+                score: 2,
+                trace: vec![
+                    Step::Hit(Match::Lit('x'), 'x'),
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::SkipPattern(Match::Lit('b')),
+                    Step::SkipPattern(Match::Lit('c')),
+                ],
+            }
+        }
+
+        pub fn fail_costs_look() -> Self {
+            Self {
+                pattern: pattern(vec![lit('a'), look("(?m:^)"), lit('b')]),
+                text: text("ab"),
+                score: 2,
+                trace: vec![
+                    Step::SkipPattern(Match::Lit('a')),
+                    Step::SkipText('a'),
+                    Step::Hit(Match::Lit('b'), 'b'),
+                ],
+            }
+        }
+
+        pub fn fail_protected_1() -> Self {
+            Self {
+                pattern: pattern(lits("a\"b")),
+                text: text("ab\""),
+                score: 2,
+                trace: vec![
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::SkipText('b'),
+                    Step::Hit(Match::Lit('"'), '"'),
+                    Step::SkipPattern(Match::Lit('b')),
+                ],
+            }
+        }
+
+        pub fn fail_protected_2() -> Self {
+            Self {
+                pattern: pattern(vec![lit('a'), rep(vec![class("[a-z]")])]),
+                text: text("\nab"),
+                score: 5,
+                trace: vec![
+                    Step::SkipText('\n'),
+                    Step::Hit(Match::Lit('a'), 'a'),
+                    Step::Hit(patt_class("[a-z]"), 'b'),
+                ],
+            }
+        }
+    }
+
+    pub fn patt_class(regex: &str) -> Match {
+        let wildcard_class = match regex_syntax::parse(regex).unwrap().into_kind() {
+            HirKind::Class(c) => c,
+            unsupported => panic!("Unexpected regex_syntax for class: {:?}", unsupported),
+        };
+
+        Match::Class(Class::from(wildcard_class))
+    }
+
+    pub fn pattern(elems: Vec<Element>) -> Pattern<Element> {
+        Pattern { elems }
+    }
+
+    pub fn text(text: &str) -> Atoms {
+        Atoms::new(text, Tokens::Chars)
+    }
+
+    pub fn words(text: &str) -> Atoms {
+        Atoms::new(text, Tokens::Words)
+    }
+
+    pub fn lines(text: &str) -> Atoms {
+        Atoms::new(text, Tokens::Lines)
+    }
 
     pub fn lits(cs: &str) -> Vec<Element> {
         cs.chars().map(|c| lit(c)).collect()
@@ -520,6 +1866,23 @@ pub mod test_cases {
         Element::Match(Match::Lit(c))
     }
 
+    pub fn caseless(c: char) -> Element {
+        Element::Match(Match::CaselessLit(c))
+    }
+
+    pub fn look(regex: &str) -> Element {
+        Element::Look(patt_look(regex))
+    }
+
+    pub fn patt_look(regex: &str) -> Look {
+        let hir_look = match regex_syntax::parse(regex).unwrap().into_kind() {
+            HirKind::Look(l) => l,
+            unsupported => panic!("Unexpected regex_syntax for look: {:?}", unsupported),
+        };
+
+        Look::from(hir_look)
+    }
+
     pub fn class(regex: &str) -> Element {
         let wildcard_class = match regex_syntax::parse(regex).unwrap().into_kind() {
             HirKind::Class(c) => c,
@@ -536,20 +1899,148 @@ pub mod test_cases {
     pub fn rep_min(minimum: usize, elems: Vec<Element>) -> Element {
         let maximum = None;
         let inner = Pattern { elems };
-        Element::Repetition(Repetition { minimum, maximum, inner })
+        Element::Repetition(Repetition { minimum, maximum, greedy: true, inner })
     }
 
     pub fn rep_bound(minimum: usize, maximum: usize, elems: Vec<Element>) -> Element {
         let max_opt = Some(maximum);
         let inner = Pattern { elems };
-        Element::Repetition(Repetition { minimum, maximum: max_opt, inner })
+        Element::Repetition(Repetition { minimum, maximum: max_opt, greedy: true, inner })
+    }
+
+    /// Makes a repetition lazy, e.g. `a*?` rather than `a*`.
+    pub fn lazy(elem: Element) -> Element {
+        match elem {
+            Element::Repetition(repetition) => Element::Repetition(Repetition { greedy: false, ..repetition }),
+            other => panic!("Expected a repetition: {:?}", other),
+        }
     }
 
     pub fn alt(left: Vec<Element>, right: Vec<Element>) -> Element {
         Element::Alternative(Pattern { elems: left }, Pattern { elems: right })
     }
 
-    pub fn capture(elems: Vec<Element>) -> Element {
-        Element::Capture(Pattern { elems })
+    pub fn capture(index: usize, elems: Vec<Element>) -> Element {
+        Element::Capture(Group { index, name: None }, Pattern { elems })
+    }
+
+    pub fn named_capture(index: usize, name: &str, elems: Vec<Element>) -> Element {
+        Element::Capture(Group { index, name: Some(name.to_string()) }, Pattern { elems })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Atoms, CompiledPattern, Options, Output, Tokens, fuzzy_find_iter};
+    use crate::diff_output::DiffOutput;
+    use crate::regex_pattern::parse_pattern;
+    use crate::table_solution::solve;
+    use crate::error::Error;
+    use std::ops::Range;
+    use test_case::test_case;
+
+    // restarting a leading repetition is part of a match, so it does not skip text for free
+    #[test_case("(?:ab)*c", "xxabZZZZcyyababc", &[(8..9, "c"), (11..16, "ababc")])]
+    #[test_case("(?:ab){2}c", "abZZZZababcyyabbabc", &[(6..11, "ababc"), (13..19, "ab{+b+}abc")])]
+    fn test_find_iter_leading_repetition(pattern: &str, text: &str, expected: &[(Range<usize>, &str)]) {
+        let outputs = fuzzy_find_iter(pattern.to_string(), text.to_string(), &Options::default(), 1).unwrap();
+        let actual: Vec<_> = outputs
+            .map(|output| output.map(|output| (output.range, output.diff.to_string())))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let expected: Vec<_> = expected.iter().map(|(range, diff)| (range.clone(), diff.to_string())).collect();
+        assert_eq!(expected, actual);
+    }
+
+    #[test_case("abc", &["abc", "ab", "abc abc abc"])]
+    #[test_case("a(b|c)*d", &["abcbx", "ad", "a b c b d"])]
+    #[test_case("(?i)hello world", &["Hello, world", "hello\nworld", "HELLO WORLD!"])]
+    #[test_case("[0-9]{1,3}", &["1234", "1 2 3 4", ""])]
+    fn test_compiled_same_as_solve(pattern: &str, texts: &[&str]) {
+        for tokens in [Tokens::Chars, Tokens::Words, Tokens::Lines] {
+            let options = Options { tokens, ..Options::default() };
+            let compiled = CompiledPattern::new(pattern, &options).unwrap();
+            for text in texts {
+                let core = parse_pattern(pattern, false).unwrap().desugar();
+                let expected = solve(&core, &Atoms::new(text, tokens), &options).unwrap();
+                let actual = compiled.match_text(text).unwrap();
+                assert_eq!(expected.score, actual.score);
+                assert_eq!(expected.range, actual.range);
+                assert_eq!(DiffOutput::new(&expected.score, &expected.trace).to_string(), actual.diff.to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn test_compiled_match_text() {
+        let compiled = CompiledPattern::new("colou?r", &Options::default()).unwrap();
+        let expected = [(0, "color"), (0, "colour"), (1, "col[-o-]r"), (4, "{+the +}colour"), (0, "color")];
+        for (text, (score, diff)) in ["color", "colour", "colr", "the colour", "color"].into_iter().zip(expected) {
+            let actual = compiled.match_text(text).unwrap();
+            assert_eq!((score, diff.to_string()), (actual.score, actual.diff.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_compiled_match_all() {
+        let compiled = CompiledPattern::new("a(b|c)d", &Options::default()).unwrap();
+        assert_eq!(vec![(0, "abd".to_string())], compiled_diffs(compiled.match_all("abd", 5)));
+        assert_eq!(
+            vec![(2, "a[-?-]{+x+}d".to_string()), (2, "a[-?-]{+x+}d".to_string())],
+            compiled_diffs(compiled.match_all("axd", 5))
+        );
+        assert_eq!(vec![(1, "a[-?-]d".to_string())], compiled_diffs(compiled.match_all("ad", 5)));
+    }
+
+    #[test]
+    fn test_compiled_match_k_best() {
+        let compiled = CompiledPattern::new("a(b|c)d", &Options::default()).unwrap();
+        assert_eq!(
+            vec![(0, "abd".to_string()), (2, "ab[-d-]{+d+}".to_string())],
+            compiled_diffs(compiled.match_k_best("abd", 2))
+        );
+        assert_eq!(
+            vec![(1, "a[-?-]d".to_string()), (3, "a[-?d-]{+d+}".to_string())],
+            compiled_diffs(compiled.match_k_best("ad", 2))
+        );
+    }
+
+    #[test]
+    fn test_compiled_find_iter() {
+        let compiled = CompiledPattern::new("ab+c", &Options::default()).unwrap();
+        let find = |text| compiled.find_iter(text, 1)
+            .map(|output| output.map(|output| (output.range, output.diff.to_string())))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(vec![(2..5, "abc".to_string()), (7..11, "abbc".to_string())], find("xxabcyyabbcz"));
+        assert_eq!(vec![(0..5, "abbbc".to_string()), (6..8, "a[-b-]c".to_string())], find("abbbc ac"));
+    }
+
+    fn compiled_diffs(outputs: Result<Vec<Output>, Error>) -> Vec<(usize, String)> {
+        outputs.unwrap().into_iter().map(|output| (output.score, output.diff.to_string())).collect()
+    }
+
+    #[test]
+    fn test_compiled_invalid_pattern() {
+        assert!(matches!(CompiledPattern::new("(a", &Options::default()), Err(Error::PatternNotRegex(_))));
+    }
+
+    #[test]
+    fn test_compiled_across_threads() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<CompiledPattern>();
+
+        let compiled = CompiledPattern::new("v=([0-9]*)", &Options::default()).unwrap();
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4)
+                .map(|i| {
+                    let compiled = &compiled;
+                    scope.spawn(move || compiled.match_text(&format!("v={}", i)).unwrap().captures.to_string())
+                })
+                .collect();
+            for (i, handle) in handles.into_iter().enumerate() {
+                assert_eq!(format!("1: \"{}\"", i), handle.join().unwrap());
+            }
+        });
     }
 }
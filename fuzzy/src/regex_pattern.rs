@@ -1,16 +1,208 @@
 //! Parses pattern using [`regex_syntax`](https://docs.rs/regex-syntax).
 //!
-//! [`regex_syntax`](https://docs.rs/regex-syntax) sometimes uses bytes in their API, while this
-//! crate currently operates on unicode characters. For now, we are getting around this by naively
-//! assuming all characters are ASCII. We will change this in the future.
+//! [`regex_syntax`](https://docs.rs/regex-syntax) uses bytes in parts of their API, while this
+//! crate operates on unicode characters. We parse with regex_syntax's default UTF-8 mode, which
+//! guarantees that literals are valid UTF-8 and byte classes only contain ASCII.
+//!
+//! regex_syntax implements case-insensitive literals, e.g. `(?i)a`, as classes, e.g. `[Aa]`. This
+//! loses the original case of the literal, which we need to tell case-only changes apart from
+//! exact hits. So we translate the parts of the AST that contain case-insensitive literals
+//! ourselves, keeping track of the flags, and turn those literals into [`Match::CaselessLit`].
+//! Every other part of the AST still goes through regex_syntax's translation into HIR.
 
-use regex_syntax::hir;
-use crate::{Class, Element, Match, Pattern, Repetition};
+use regex_syntax::{ast, hir};
+use crate::{Class, Element, Group, Look, Match, Pattern, Repetition};
 use crate::error::Error;
 
-pub fn parse_pattern(pattern: &str) -> Result<Pattern<Element>, Error> {
-    let hir = regex_syntax::parse(pattern)?;
-    return wrap(parse_impl(&hir));
+/// Parses the regex pattern. If `case_insensitive` is set, the entire pattern ignores case, as if
+/// it started with `(?i)`.
+pub fn parse_pattern(pattern: &str, case_insensitive: bool) -> Result<Pattern<Element>, Error> {
+    let ast = ast::parse::Parser::new().parse(pattern)
+        .map_err(regex_syntax::Error::from)?;
+    let mut flags = Flags { case_insensitive, ..Flags::default() };
+    return wrap(translate(pattern, &ast, &mut flags));
+}
+
+/// The flags which regex_syntax's translation into HIR depends on.
+#[derive(Clone, Copy)]
+struct Flags {
+    case_insensitive: bool,
+    multi_line: bool,
+    dot_matches_new_line: bool,
+    swap_greed: bool,
+    unicode: bool,
+    crlf: bool,
+}
+
+impl Default for Flags {
+    fn default() -> Self {
+        Flags {
+            case_insensitive: false,
+            multi_line: false,
+            dot_matches_new_line: false,
+            swap_greed: false,
+            unicode: true,
+            crlf: false,
+        }
+    }
+}
+
+impl Flags {
+    fn set(&mut self, flags: &ast::Flags) {
+        let fields = [
+            (ast::Flag::CaseInsensitive, &mut self.case_insensitive),
+            (ast::Flag::MultiLine, &mut self.multi_line),
+            (ast::Flag::DotMatchesNewLine, &mut self.dot_matches_new_line),
+            (ast::Flag::SwapGreed, &mut self.swap_greed),
+            (ast::Flag::Unicode, &mut self.unicode),
+            (ast::Flag::CRLF, &mut self.crlf),
+        ];
+        for (flag, field) in fields {
+            if let Some(state) = flags.flag_state(flag) {
+                *field = state;
+            }
+        }
+    }
+
+    /// Sets the flags which `ast` leaves set for the rest of the enclosing group.
+    ///
+    /// Like regex_syntax, a flag such as `(?i)` applies until the end of the enclosing group,
+    /// including any later alternative branches.
+    fn set_from(&mut self, ast: &ast::Ast) {
+        match ast {
+            ast::Ast::Flags(set_flags) => self.set(&set_flags.flags),
+            ast::Ast::Alternation(ast::Alternation { asts, .. }) | ast::Ast::Concat(ast::Concat { asts, .. }) => {
+                for ast in asts {
+                    self.set_from(ast);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn translator(&self) -> hir::translate::Translator {
+        hir::translate::TranslatorBuilder::new()
+            .case_insensitive(self.case_insensitive)
+            .multi_line(self.multi_line)
+            .dot_matches_new_line(self.dot_matches_new_line)
+            .swap_greed(self.swap_greed)
+            .unicode(self.unicode)
+            .crlf(self.crlf)
+            .build()
+    }
+}
+
+/// Translates `ast` with the given flags, updating them with any flags it sets.
+fn translate(pattern: &str, ast: &ast::Ast, flags: &mut Flags) -> Result<Vec<Element>, Error> {
+    if !has_caseless(ast, &mut flags.case_insensitive.clone()) {
+        let hir = flags.translator()
+            .translate(pattern, ast)
+            .map_err(regex_syntax::Error::from)?;
+        flags.set_from(ast);
+        return parse_impl(&hir);
+    }
+    match ast {
+        ast::Ast::Literal(lit) => {
+            Ok(vec![Element::Match(Match::CaselessLit(lit.c))])
+        }
+        ast::Ast::Repetition(repetition) => {
+            let (minimum, maximum) = match &repetition.op.kind {
+                ast::RepetitionKind::ZeroOrOne => (0, Some(1)),
+                ast::RepetitionKind::ZeroOrMore => (0, None),
+                ast::RepetitionKind::OneOrMore => (1, None),
+                ast::RepetitionKind::Range(ast::RepetitionRange::Exactly(n)) => (*n, Some(*n)),
+                ast::RepetitionKind::Range(ast::RepetitionRange::AtLeast(n)) => (*n, None),
+                ast::RepetitionKind::Range(ast::RepetitionRange::Bounded(m, n)) => (*m, Some(*n)),
+            };
+            let inner = translate(pattern, &repetition.ast, &mut flags.clone())?;
+            // like regex_syntax, `a{0}` is empty and `a{1}` is just `a`
+            match (minimum, maximum) {
+                (0, Some(0)) => Ok(vec![]),
+                (1, Some(1)) => Ok(inner),
+                _ => {
+                    let minimum = minimum.try_into().map_err(|_| Error::RegexBoundTooLarge)?;
+                    let maximum = match maximum {
+                        Some(max) => Some(max.try_into().map_err(|_| Error::RegexBoundTooLarge)?),
+                        None => None,
+                    };
+                    let greedy = repetition.greedy != flags.swap_greed;
+                    Ok(vec![Element::Repetition(Repetition { minimum, maximum, greedy, inner: Pattern { elems: inner } })])
+                }
+            }
+        }
+        ast::Ast::Group(group) => {
+            let mut inner_flags = *flags;
+            if let Some(group_flags) = group.flags() {
+                inner_flags.set(group_flags);
+            }
+            let inner = translate(pattern, &group.ast, &mut inner_flags)?;
+            match &group.kind {
+                ast::GroupKind::CaptureIndex(index) => {
+                    let group = Group { index: *index as usize, name: None };
+                    Ok(vec![Element::Capture(group, Pattern { elems: inner })])
+                }
+                ast::GroupKind::CaptureName { name, .. } => {
+                    let group = Group { index: name.index as usize, name: Some(name.name.clone()) };
+                    Ok(vec![Element::Capture(group, Pattern { elems: inner })])
+                }
+                ast::GroupKind::NonCapturing(_) => Ok(inner),
+            }
+        }
+        ast::Ast::Concat(concat) => {
+            let mut elems = vec![];
+            for ast in &concat.asts {
+                elems.extend(translate(pattern, ast, flags)?);
+            }
+            Ok(elems)
+        }
+        ast::Ast::Alternation(alternation) => {
+            let mut elems: Option<Vec<Element>> = None;
+            for ast in &alternation.asts {
+                let p2 = wrap(translate(pattern, ast, flags))?;
+                elems = Some(match elems {
+                    Some(elems) => vec![Element::Alternative(Pattern { elems }, p2)],
+                    None => p2.elems,
+                });
+            }
+            Ok(elems.unwrap_or_default())
+        }
+        _ => unreachable!("only literals and the nodes that contain them have caseless literals"),
+    }
+}
+
+/// Whether `ast` contains a case-insensitive literal, when `case_insensitive` starts out as given.
+fn has_caseless(ast: &ast::Ast, case_insensitive: &mut bool) -> bool {
+    match ast {
+        ast::Ast::Flags(set_flags) => {
+            if let Some(state) = set_flags.flags.flag_state(ast::Flag::CaseInsensitive) {
+                *case_insensitive = state;
+            }
+            false
+        }
+        ast::Ast::Literal(lit) => *case_insensitive && has_case(lit.c),
+        ast::Ast::Repetition(repetition) => {
+            has_caseless(&repetition.ast, &mut case_insensitive.clone())
+        }
+        ast::Ast::Group(group) => {
+            let mut inner = group.flags()
+                .and_then(|flags| flags.flag_state(ast::Flag::CaseInsensitive))
+                .unwrap_or(*case_insensitive);
+            has_caseless(&group.ast, &mut inner)
+        }
+        ast::Ast::Alternation(ast::Alternation { asts, .. }) | ast::Ast::Concat(ast::Concat { asts, .. }) => {
+            // no short-circuiting, so later flags are still set
+            let mut found = false;
+            for ast in asts {
+                found |= has_caseless(ast, case_insensitive);
+            }
+            found
+        }
+        _ => false,
+    }
+}
+
+fn has_case(c: char) -> bool {
+    c.to_lowercase().ne(c.to_uppercase())
 }
 
 fn wrap(try_elems: Result<Vec<Element>, Error>) -> Result<Pattern<Element>, Error> {
@@ -21,16 +213,24 @@ fn wrap(try_elems: Result<Vec<Element>, Error>) -> Result<Pattern<Element>, Erro
 fn parse_impl(hir: &hir::Hir) -> Result<Vec<Element>, Error>
 {
     match hir.kind() {
+        hir::HirKind::Empty => {
+            Ok(vec![])
+        }
         hir::HirKind::Literal(hir::Literal(ref bytes)) => {
-            // TODO modify Patt::Lit to use bytes rather then chars. For now, assuming ascii
-            Ok(bytes.iter().map(|b| Element::Match(Match::Lit(*b as char))).collect())
+            let lit = std::str::from_utf8(bytes)
+                .map_err(|_| Error::PatternUnsupported(format!("non UTF-8 literal {:?}", bytes)))?;
+            Ok(lit.chars().map(|c| Element::Match(Match::Lit(c))).collect())
         }
         hir::HirKind::Class(class) => {
             Ok(vec![Element::Match(Match::Class(Class::from(class.clone())))])
         }
-        hir::HirKind::Capture(hir::Capture { sub, .. }) => {
+        hir::HirKind::Look(look) => {
+            Ok(vec![Element::Look(Look::from(*look))])
+        }
+        hir::HirKind::Capture(hir::Capture { index, name, sub }) => {
            let pattern = wrap(parse_impl(sub))?;
-           Ok(vec![Element::Capture(pattern)])
+           let group = Group { index: *index as usize, name: name.as_ref().map(|n| n.to_string()) };
+           Ok(vec![Element::Capture(group, pattern)])
         }
         hir::HirKind::Alternation(children) => {
             match &children[..] {
@@ -53,34 +253,51 @@ fn parse_impl(hir: &hir::Hir) -> Result<Vec<Element>, Error>
                 }
             }
         }
-        hir::HirKind::Repetition(hir::Repetition { min, max, sub, .. }) => {
+        hir::HirKind::Repetition(hir::Repetition { min, max, greedy, sub }) => {
             Result::from_iter(
                 wrap(parse_impl(sub)).map(|inner| {
                     let minimum = (*min).try_into().map_err(|_| Error::RegexBoundTooLarge)?;
                     let maximum = max.map_or(Ok(None), |max|
                         max.try_into().map(|m| Some(m)).map_err(|_| Error::RegexBoundTooLarge)
                     )?;
-                    Ok(Element::Repetition(Repetition { minimum, maximum, inner }))
+                    Ok(Element::Repetition(Repetition { minimum, maximum, greedy: *greedy, inner }))
                 })
             )
         }
         hir::HirKind::Concat(subs) => {
             let try_nested: Result<Vec<Vec<Element>>, Error> =
-                Result::from_iter(subs.iter().map(|sub| parse_impl(sub)));
+                Result::from_iter(subs.iter().map(parse_impl));
             try_nested.map(|nested| nested.into_iter().flatten().collect())
         }
-        unsupported => {
-            Err(Error::PatternUnsupported(format!("{:?}", unsupported)))
-        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::test_cases::{alt, class, capture, lit, lits, rep, rep_min, rep_bound};
+    use crate::test_cases::{alt, caseless, class, capture, lazy, lit, lits, look, named_capture, rep, rep_min, rep_bound};
     use proptest::prelude::*;
 
+    #[test]
+    fn parse_empty_1() {
+        parse_test("", vec![]);
+    }
+
+    #[test]
+    fn parse_empty_2() {
+        parse_test("()", vec![capture(1, vec![])]);
+    }
+
+    #[test]
+    fn parse_empty_3() {
+        parse_test("ab|", vec![alt(lits("ab"), vec![])]);
+    }
+
+    #[test]
+    fn parse_empty_4() {
+        parse_test("(?:)*a", vec![rep_bound(0, 1, vec![]), lit('a')]);
+    }
+
     #[test]
     fn parse_lit_1() {
         parse_test("a", lits("a"));
@@ -91,6 +308,16 @@ mod tests {
         parse_test("abc", lits("abc"));
     }
 
+    #[test]
+    fn parse_lit_3() {
+        parse_test("café", lits("café"));
+    }
+
+    #[test]
+    fn parse_lit_4() {
+        parse_test("日本 ©", lits("日本 ©"));
+    }
+
     #[test]
     fn parse_wildcard() {
         parse_test(".", vec![class(".")])
@@ -101,6 +328,68 @@ mod tests {
         parse_test("a.", vec![lit('a'), class(".")]);
     }
 
+    #[test]
+    fn parse_look_1() {
+        parse_test("^a$", vec![look("^"), lit('a'), look("$")]);
+    }
+
+    #[test]
+    fn parse_look_2() {
+        parse_test(r"(?m)^a\b", vec![look("(?m:^)"), lit('a'), look(r"\b")]);
+    }
+
+    #[test]
+    fn parse_caseless_1() {
+        parse_test("(?i)aB", vec![caseless('a'), caseless('B')]);
+    }
+
+    #[test]
+    fn parse_caseless_2() {
+        parse_test("a(?i)b|c", vec![alt(vec![lit('a'), caseless('b')], vec![caseless('c')])]);
+    }
+
+    #[test]
+    fn parse_caseless_3() {
+        parse_test("(?i:A1)b", vec![caseless('A'), lit('1'), lit('b')]);
+    }
+
+    #[test]
+    fn parse_caseless_4() {
+        parse_test("(?i)a*|b", vec![alt(vec![rep(vec![caseless('a')])], vec![caseless('b')])]);
+    }
+
+    #[test]
+    fn parse_caseless_5() {
+        parse_test("(?i)[a-c]", vec![class("(?i)[a-c]")]);
+    }
+
+    #[test]
+    fn parse_caseless_6() {
+        let expected_pattern = Pattern { elems: vec![caseless('A'), lit('-'), caseless('b')] };
+        let actual_pattern = parse_pattern("A-b", true).expect("Cannot parse pattern");
+        assert_eq!(expected_pattern, actual_pattern);
+    }
+
+    #[test]
+    fn parse_caseless_7() {
+        parse_test("(?i)a\u{100000}\u{10FFFD}", vec![caseless('a'), lit('\u{100000}'), lit('\u{10FFFD}')]);
+    }
+
+    #[test]
+    fn parse_caseless_8() {
+        parse_test("(?iU)(a)+(?-U)b?", vec![lazy(rep_min(1, vec![capture(1, vec![caseless('a')])])), rep_bound(0, 1, vec![caseless('b')])]);
+    }
+
+    #[test]
+    fn parse_caseless_9() {
+        parse_test("(?i)a{1}b{0}", vec![caseless('a')]);
+    }
+
+    #[test]
+    fn parse_caseless_10() {
+        parse_test("(?i)(?s:.)(?P<x>a)", vec![class("(?s:.)"), named_capture(1, "x", vec![caseless('a')])]);
+    }
+
     #[test]
     fn parse_repetition_1() {
         parse_test("a*", vec![rep(lits("a"))]);
@@ -126,9 +415,32 @@ mod tests {
         parse_test("a{4}", vec![rep_bound(4, 4, lits("a"))]);
     }
 
+    #[test]
+    fn parse_repetition_lazy_1() {
+        parse_test("a*?", vec![lazy(rep(lits("a")))]);
+    }
+
+    #[test]
+    fn parse_repetition_lazy_2() {
+        parse_test("a{1,3}?", vec![lazy(rep_bound(1, 3, lits("a")))]);
+    }
+
     #[test]
     fn parse_group_1() {
-        parse_test("(a)", vec![capture(lits("a"))]);
+        parse_test("(a)", vec![capture(1, lits("a"))]);
+    }
+
+    #[test]
+    fn parse_group_2() {
+        parse_test("(?P<project>a)", vec![named_capture(1, "project", lits("a"))]);
+    }
+
+    #[test]
+    fn parse_group_3() {
+        parse_test(
+            "(a(?P<inner>b))(c)",
+            vec![capture(1, vec![lit('a'), named_capture(2, "inner", lits("b"))]), capture(3, lits("c"))]
+        );
     }
 
     #[test]
@@ -143,40 +455,38 @@ mod tests {
 
     fn parse_test(pattern: &str, expected_elems: Vec<Element>) {
         let expected_pattern = Pattern { elems: expected_elems };
-        let actual_pattern = parse_pattern(&pattern).expect("Cannot parse pattern");
+        let actual_pattern = parse_pattern(&pattern, false).expect("Cannot parse pattern");
         assert_eq!(expected_pattern, actual_pattern);
     }
 
-    // TODO support parsing empty patterns
-    // TODO more accurate range of literal patterns here
-    const LITERAL_PATTERN_REGEX: &str = "[[:alnum:]]+";
+    const LITERAL_PATTERN_REGEX: &str = "[\\pL\\pN]*";
 
     proptest! {
         #[test]
         fn smoketest(pattern in "\\PC*") {
-            let _ = parse_pattern(&pattern);
+            let _ = parse_pattern(&pattern, false);
         }
 
         #[test]
         fn literals(pattern in LITERAL_PATTERN_REGEX) {
             let expected_pattern = Pattern { elems: lits(&pattern) };
-            let actual_pattern = parse_pattern(&pattern).expect("Cannot parse pattern");
+            let actual_pattern = parse_pattern(&pattern, false).expect("Cannot parse pattern");
             prop_assert_eq!(expected_pattern, actual_pattern);
         }
 
         #[test]
         fn captures(inner in LITERAL_PATTERN_REGEX) {
             let wrapped = format!("({})", inner);
-            let Pattern { elems: actual_inner } = parse_pattern(&inner).expect("Cannot parse inner");
-            let Pattern { elems: actual_wrapped } = parse_pattern(&wrapped).expect("Cannot parse wrapped");
-            prop_assert_eq!( actual_wrapped, vec![capture(actual_inner)]);
+            let Pattern { elems: actual_inner } = parse_pattern(&inner, false).expect("Cannot parse inner");
+            let Pattern { elems: actual_wrapped } = parse_pattern(&wrapped, false).expect("Cannot parse wrapped");
+            prop_assert_eq!( actual_wrapped, vec![capture(1, actual_inner)]);
         }
 
         #[test]
         fn alternatives(inners in prop::collection::vec(LITERAL_PATTERN_REGEX, 2..5)) {
             // the regex lib is smart enough to turn an alternative of single characters into a
             // character class ... which is good, but annoying for this particular test
-            prop_assume!(inners.iter().any(|inner| inner.len() > 1));
+            prop_assume!(inners.iter().any(|inner| inner.chars().count() > 1));
 
             let alt_pattern = inners.join("|");
             let expected_alt = inners.iter()
@@ -184,7 +494,7 @@ mod tests {
                 .reduce(|acc, right| vec![alt(acc, right)]).expect("Cannot be empty");
 
             let expected_pattern = Pattern { elems: expected_alt };
-            let actual_pattern = parse_pattern(&alt_pattern).expect("Cannot parse pattern");
+            let actual_pattern = parse_pattern(&alt_pattern, false).expect("Cannot parse pattern");
             prop_assert_eq!(expected_pattern, actual_pattern);
         }
     }
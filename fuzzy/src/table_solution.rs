@@ -1,113 +1,213 @@
 //! A theoretically faster solver than my initial cached recursive implementation.
 //!
-//! This implementation pre-allocates a [vector](State) storing state for all [nodes](Ix), so in
-//! theory it should be relatively efficient, although we haven't done any benchmarks yet. We will
-//! do these in the future.
+//! This implementation pre-allocates a [vector](State) storing a small [node](Node) for every
+//! [index](Ix), and explores it depth first with an explicit stack. The `table_solution` benchmark
+//! measures how long this takes, and how much memory it needs.
+//!
+//! The [`linear_space`] solver finds the same solution without storing every node at once, while
+//! the [`alternatives`] module finds other good solutions using the same table.
+
+pub mod alternatives;
+pub mod linear_space;
 
-use crate::{Atoms, ElementCore, Match, Pattern, Solution, Step};
+use crate::{Atoms, Band, Bounds, Costs, ElementCore, Look, Match, Options, Pattern, Solution, Solver, Step, TieBreak, Tokens};
 use crate::error::Error;
 use crate::flat_pattern::{Flat, FlatPattern};
-use nonempty::{NonEmpty, nonempty};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+
+/// The cost of a step we can never take. Any score including this cost is also forbidden.
+const FORBIDDEN: usize = usize::MAX;
+
+/// Finds the optimal match between the pattern and text.
+///
+/// We split the pattern into the same kind of tokens as the text. When [`Options::search`] is set,
+/// the trace leaves out the free text skips before and after the match.
+pub fn solve(pattern: &Pattern<ElementCore>, text: &Atoms, options: &Options) -> Result<Solution, Error> {
+    let flat = flatten(pattern, text.tokens, options.ignore_whitespace);
+    solve_flat(&Arc::new(flat), text, options)
+}
+
+/// Flattens the pattern for the solvers, so we can reuse it for every text we split into the same
+/// kind of tokens.
+pub fn flatten(pattern: &Pattern<ElementCore>, tokens: Tokens, ignore_whitespace: bool) -> FlatPattern {
+    FlatPattern::custom(pattern, 1, tokens, ignore_whitespace)
+}
+
+/// Finds the optimal match between a pattern we already flattened, per [`flatten`], and the text.
+pub fn solve_flat(pattern: &Arc<FlatPattern>, text: &Atoms, options: &Options) -> Result<Solution, Error> {
+    let conf = Config::from_flat(pattern.clone(), text, options);
+    match options.solver {
+        Solver::Table => solve_table(&conf, options),
+        Solver::LinearSpace => linear_space::solve(&conf, options),
+    }
+}
+
+fn solve_table(conf: &Config, options: &Options) -> Result<Solution, Error> {
+    match options.band.filter(|_| !conf.search) {
+        Some(band) => solve_banded(conf, options, band),
+        None => solve_within(conf, options, None),
+    }
+}
+
+/// Finds the optimal match inside the band, widening it if necessary.
+///
+/// Any match which leaves the band costs at least [`Config::band_escape_cost`], so if the optimal
+/// match inside the band costs less, it is also the optimal match overall.
+fn solve_banded(conf: &Config, options: &Options, band: Band) -> Result<Solution, Error> {
+    let widths = conf.widths();
+    // once the band is this wide, it contains every node
+    let full_width = widths.iter().map(|(min, _)| *min).max().unwrap_or(0).max(conf.text.len());
+    let mut width = band.width;
+    loop {
+        if width >= full_width {
+            return solve_within(conf, options, None);
+        }
+        let bounds = widths.iter()
+            .map(|(min, max)| min.saturating_sub(width)..=max.map_or(usize::MAX, |max| max.saturating_add(width)))
+            .collect::<Vec<_>>();
+        let result = solve_within(conf, options, Some(bounds));
+        let escape_cost = conf.band_escape_cost(width);
+        let is_optimal = match &result {
+            Ok(solution) => solution.score < escape_cost,
+            Err(Error::NoMatchWithinMaxCost(max_cost)) => *max_cost < escape_cost,
+            Err(Error::NoPossibleMatch) => false,
+            Err(_) => true,
+        };
+        if is_optimal || !band.adaptive {
+            return result.map_err(|err| match err {
+                Error::NoPossibleMatch => Error::NoMatchWithinBand(width),
+                err => err,
+            });
+        }
+        width = width.saturating_mul(2).max(1);
+    }
+}
 
-pub fn solve(pattern: &Pattern<ElementCore>, text: &Atoms) -> Result<Solution, Error> {
-    let conf = Config::new(pattern, text);
-    let mut state = State::new(&conf);
+/// Finds the optimal match using nodes whose text index is inside the bounds for their pattern
+/// index, or every node if there are no bounds.
+fn solve_within(conf: &Config, options: &Options, bounds: Option<Vec<RangeInclusive<usize>>>) -> Result<Solution, Error> {
+    let mut state = State::new(conf, options.limits.max_nodes, bounds)?;
 
     let start_ix = conf.start();
     let end_ix = conf.end();
 
-    let _ = calculate_optimal_path(&conf, &mut state)?;
+    let max_cost = conf.budget(options.max_cost.unwrap_or(usize::MAX));
+    let _ = calculate_optimal_path(conf, &mut state, max_cost, options.limits.max_steps)?;
 
     let start_node = state.get(start_ix);
-    let score = start_node.done_info()
-        .map(|i| i.0)
-        .map_err(|_| Error::IncompleteFinalState)?;
+    if !start_node.is_finished() {
+        return Err(Error::IncompleteFinalState);
+    }
+    let score = conf.score(start_node.score());
+    check_score(score, options)?;
 
-    let mut trace = vec![];
+    // we only store the index of each optimal step type, so we find the step types again
+    let mut path = vec![];
     let mut from = start_ix;
-    loop {
+    while from != end_ix {
         let node = state.get(from);
-        if !node.is_done() || from == end_ix { break; }
-        let (patt, text) = conf.get(from);
-        let (_, step_type, next) = node.done_info()?;
-        if let Some(step) =  step_type.step() {
-            let final_step = step.map(
-                |_| match patt {
-                    Some(Flat::Lit(c))   => Match::Lit(*c),
-                    Some(Flat::Class(c)) => Match::Class(c.clone()),
-                    unexpected           => panic!("Unexpected trace pattern {:?}", unexpected),
-                },
-                |_| match text {
-                    Some(c) => *c,
-                    unexpected         => panic!("Unexpected trace text {:?}", unexpected),
-                }
-            );
-            trace.push(final_step);
-        }
+        let step_type = conf.node_step_types(&from).get(node.step as usize)
+            .filter(|_| node.status == Status::Done)
+            .ok_or(Error::IncompleteFinalState)?;
+        let next = conf.step(from, step_type);
+        path.push((from, step_type, next));
         from = next;
     }
-    if from != end_ix {
-        return Err(Error::IncompleteFinalState);
+
+    solution(conf, score, &path)
+}
+
+/// Checks that the optimal score is allowed, and within [`Options::max_cost`].
+fn check_score(score: usize, options: &Options) -> Result<(), Error> {
+    if let Some(max_cost) = options.max_cost.filter(|max_cost| score > *max_cost) {
+        return Err(Error::NoMatchWithinMaxCost(max_cost));
+    }
+    if score == FORBIDDEN {
+        return Err(Error::NoPossibleMatch);
     }
+    Ok(())
+}
 
-    Ok(Solution { score, trace })
+/// Converts the optimal path, i.e. the optimal step from each node to the next, into a solution.
+fn solution(conf: &Config, score: usize, path: &[(Ix, StepType, Ix)]) -> Result<Solution, Error> {
+    let mut trace = vec![];
+    let mut range = 0..conf.text.len();
+    let mut started = false;
+    for (from, step_type, next) in path.iter().copied() {
+        if conf.is_free(from, step_type) {
+            if conf.is_trailing(&from) {
+                range.end = range.end.min(from.text);
+            }
+            continue;
+        }
+        // the match starts with the first step which is not free
+        if !started {
+            range.start = from.text;
+            started = true;
+        }
+        trace.extend(conf.trace_steps(from, next, step_type)?);
+    }
+    Ok(Solution { score, trace, range })
 }
 
+/// Calculates the optimal score for each node we need, starting from the start node.
+///
+/// We explore the table depth first. The nodes we are still working on live in a stack of
+/// [frames](Frame), so the table only stores the outcome of each finished node.
+///
+/// Each node has a budget: the highest score it can have while keeping the total cost within
+/// `max_cost`. We do not explore steps which cost more than the budget. Instead, we treat them as
+/// forbidden, so a node whose score is over budget only knows that the true score is too high. If
+/// we reach the same node later with a larger budget, we have to calculate it again.
 fn calculate_optimal_path(
     conf: &Config,
     state: &mut State,
+    max_cost: usize,
+    max_steps: usize,
  ) -> Result<(), Error> {
     let start_ix = conf.start();
-    let end_ix = conf.end();
-
-    let mut loop_state = LoopState::Down(Down {
-        parent: Default::default(),
-        current: start_ix,
-    });
+    *state.get_mut(start_ix) = Node::working();
+    let mut stack = vec![Frame::new(conf, start_ix, max_cost)];
 
     let mut loop_counter = 0;
 
-    loop {
+    while let Some(frame) = stack.last_mut() {
         loop_counter += 1;
-        if loop_counter >= 1000000000 { // TODO make this max configurable
-            return Err(Error::ExceededMaxSteps(loop_counter));
-        }
-        let new_parent = match &loop_state {
-            LoopState::Down(down) if state.get(down.current).is_ready() => {
-                let (flat, text) = conf.get(down.current);
-                let opt_node_type = NodeType::get(flat, text, &down.current);
-                let node_state = state.get_mut(down.current);
-                node_state.initialise(end_ix, down.parent, down.current, opt_node_type)?;
-                down.parent
-            }
-            LoopState::Down(down) => down.parent,
-            LoopState::Back(back) => {
-                let new_child = back.child;
-                let (new_score, _, _) = state.get(new_child).done_info()?;
-                let node_state = state.get_mut(back.current);
-                let new_parent = node_state.update(new_child, back.current, new_score)?;
-                new_parent
+        if loop_counter > max_steps {
+            return Err(Error::ExceededMaxSteps(max_steps));
+        }
+
+        let Some(step_type) = frame.step_types.get(frame.current) else {
+            // we have tried every step, so the node is finished
+            let node = frame.finish(conf)?;
+            *state.get_mut(frame.ix) = node;
+            stack.pop();
+            if let Some(parent) = stack.last_mut() {
+                parent.update(conf, node.score());
             }
+            continue;
         };
 
-        let current_ix = loop_state.current();
-        let final_state = state.get(current_ix);
-        if current_ix == start_ix && final_state.is_done() {
-            break;
-        } else if final_state.is_done() {
-            loop_state = LoopState::Back(Back {
-                current: new_parent,
-                child: current_ix,
-            });
-        } else if final_state.is_working() {
-            let current_step_type = final_state.current_step_type()?;
-            let child = conf.step(current_ix, current_step_type);
-            loop_state = LoopState::Down(Down {
-                parent: current_ix,
-                current: child,
-            });
-        } else {
-            return Err(Error::NoNodeProgress(format!("{:?}", current_ix)));
+        let child = conf.step(frame.ix, step_type);
+        let cost = conf.cost(frame.ix, step_type);
+        match frame.budget.checked_sub(cost) {
+            Some(budget) if cost != FORBIDDEN && state.contains(child) => {
+                let child_node = state.get(child);
+                match child_node.status {
+                    Status::Working => return Err(Error::CyclicNode(format!("{:?}", child))),
+                    Status::Ready => {}
+                    _ if child_node.is_over_budget(budget) => {}
+                    _ => {
+                        frame.update(conf, child_node.score());
+                        continue;
+                    }
+                }
+                *state.get_mut(child) = Node::working();
+                stack.push(Frame::new(conf, child, budget));
+            }
+            // we already know this step costs too much, or leaves the band, so we don't explore it
+            _ => frame.update(conf, FORBIDDEN),
         }
     }
 
@@ -120,22 +220,254 @@ fn calculate_optimal_path(
 /// the text, while the other dimension represents an expanded pattern, per [`FlatPattern::custom`].
 pub struct Config {
     text: Vec<char>,
-    pattern: FlatPattern,
+    text_ends: Vec<usize>,
+    /// We share the flat pattern, so we only flatten a pattern once to match it against many texts.
+    pattern: Arc<FlatPattern>,
+    costs: Costs,
+    tie_break: TieBreak,
+    /// How much the table scales each cost, so we can break ties by the number of runs of skips.
+    ///
+    /// See [`Config::score`].
+    scale: usize,
+    search: bool,
+    before: Option<char>,
+    after: Option<char>,
+    /// The index of the first node for each index in a row of the table, per [`Config::cell_starts`].
+    cell_starts: Vec<usize>,
 }
 
 impl Config {
-    fn new(pattern: &Pattern<ElementCore>, text: &Atoms) -> Self {
-        let pattern = FlatPattern::custom(pattern, 1);
+    #[cfg(test)]
+    fn new(pattern: &Pattern<ElementCore>, text: &Atoms, options: &Options) -> Self {
+        let pattern = flatten(pattern, text.tokens, options.ignore_whitespace);
+        Self::from_flat(Arc::new(pattern), text, options)
+    }
+
+    fn from_flat(pattern: Arc<FlatPattern>, text: &Atoms, options: &Options) -> Self {
+        let text_ends = text.ends.clone();
+        let (before, after) = (text.before, text.after);
         let text = text.atoms.clone();
-        Config { text, pattern }
+        let costs = options.costs.clone();
+        let tie_break = options.tie_break;
+        // a match has at most one more run of skips than it has steps which move through the text
+        let scale = if tie_break.group_skips { text.len() + 2 } else { 1 };
+        let mut conf = Config {
+            text, text_ends, pattern, costs, tie_break, scale, search: options.search, before, after, cell_starts: vec![],
+        };
+        conf.cell_starts = conf.cell_starts();
+        conf
     }
 
     fn get(&self, ix: Ix) -> (Option<&Flat>, Option<&char>) {
         (self.pattern.get(ix.pattern), self.text.get(ix.text))
     }
 
+    /// Returns the index after the end of the text token starting at this index.
+    fn token_end(&self, text_ix: usize) -> usize {
+        self.text_ends.get(text_ix).copied().unwrap_or(text_ix)
+    }
+
+    /// Checks if this pattern token matches the text at this index, up to the end of the text
+    /// token.
+    fn token_matches(&self, token: &[char], ix: &Ix) -> bool {
+        let end = ix.text + token.len();
+        end == self.token_end(ix.text) && self.text.get(ix.text..end) == Some(token)
+    }
+
+    /// Checks if a single character pattern literal can hit the text at this index.
+    ///
+    /// Like a pattern token, it has to hit a whole text token. The exception is when a class
+    /// already hit the start of the text token, as classes can match part of a token.
+    fn lit_fits(&self, ix: &Ix) -> bool {
+        let starts_token = ix.text == 0 || self.token_end(ix.text - 1) == ix.text;
+        !starts_token || self.is_single_char(ix.text)
+    }
+
+    /// Checks if the text just before this index is whitespace.
+    ///
+    /// A run of pattern whitespace hits the whole run of text whitespace after it, so when a
+    /// capture group boundary splits a run of pattern whitespace in two, the second run can only
+    /// hit the whitespace the first run already matched, and matches no text instead.
+    fn after_whitespace(&self, ix: &Ix) -> bool {
+        ix.text > 0 && self.text[ix.text - 1].is_whitespace()
+    }
+
+    /// Returns the index after the end of the whitespace run starting at this index.
+    fn whitespace_end(&self, text_ix: usize) -> usize {
+        let len = self.text[text_ix..].iter().take_while(|c| c.is_whitespace()).count();
+        text_ix + len
+    }
+
+    fn cost(&self, ix: Ix, step_type: StepType) -> usize {
+        if self.is_free(ix, step_type) {
+            return 0;
+        }
+        let patt = self.pattern.get(ix.pattern);
+        let text = &self.text[ix.text..self.token_end(ix.text)];
+        let is_skip = matches!(step_type, StepType::SkipText | StepType::SkipPattern);
+        let opens_gap = is_skip && !ix.gap;
+        let gap_open = if opens_gap { self.costs.gap_open } else { 0 };
+        let cost = step_type.cost(&self.costs, patt, text).saturating_add(gap_open);
+        if cost == FORBIDDEN {
+            return FORBIDDEN;
+        }
+        let run = (opens_gap && self.tie_break.group_skips) as usize;
+        cost.checked_mul(self.scale).map_or(FORBIDDEN, |cost| cost.saturating_add(run))
+    }
+
+    /// Converts a score from the table into the score of the match.
+    ///
+    /// When we break ties by the number of runs of skips, the table scales every cost so that it
+    /// outweighs any number of runs, and adds one for each run. This way, the optimal score in the
+    /// table is also the optimal score of the match, with the fewest runs.
+    fn score(&self, score: usize) -> usize {
+        if score == FORBIDDEN { FORBIDDEN } else { score / self.scale }
+    }
+
+    /// Converts the highest score of a match into the highest score in the table.
+    fn budget(&self, max_cost: usize) -> usize {
+        max_cost.saturating_mul(self.scale).saturating_add(self.scale - 1)
+    }
+
+    /// Returns the steps we can take from this node.
+    ///
+    /// When searching, we can skip text before the pattern starts from any kind of node, including
+    /// groups and repetitions, so the match does not have to start at the beginning of the text.
+    fn step_types(&self, ix: &Ix, node_type: NodeType) -> StepTypes {
+        let mut step_types = node_type.step_types();
+        if self.is_leading(ix) && !step_types.contains(StepType::SkipText) {
+            step_types.push(StepType::SkipText);
+        }
+        self.tie_break_order(step_types)
+    }
+
+    /// Reorders the steps from a node, per [`Options::tie_break`].
+    ///
+    /// The default order is the one [`NodeType::step_types`] returns, so we only move steps to
+    /// change it.
+    fn tie_break_order(&self, mut step_types: StepTypes) -> StepTypes {
+        let is_skip = |step_type: &StepType| matches!(step_type, StepType::SkipPattern | StepType::SkipText);
+        if !self.tie_break.hits_first {
+            step_types.move_to_front(is_skip);
+        }
+        if self.tie_break.text_first {
+            step_types.swap(|step_type| *step_type == StepType::SkipPattern, |step_type| *step_type == StepType::SkipText);
+        }
+        if !self.tie_break.left_first {
+            step_types.swap(|step_type| *step_type == StepType::StartLeft, |step_type| matches!(step_type, StepType::StartRight(_)));
+        }
+        step_types
+    }
+
+    /// Returns the steps we can take from this node, or no steps if it is the end node.
+    fn node_step_types(&self, ix: &Ix) -> StepTypes {
+        NodeType::get(self, ix).map_or(StepTypes::default(), |node_type| self.step_types(ix, node_type))
+    }
+
+    /// Checks if we are searching, and have not started matching the pattern yet, per [`Ix::leading`].
+    fn is_leading(&self, ix: &Ix) -> bool {
+        self.search && ix.leading && ix.text < self.text.len()
+    }
+
+    /// Checks if we are searching, and have finished matching the pattern.
+    fn is_trailing(&self, ix: &Ix) -> bool {
+        self.search && ix.pattern == self.pattern.len()
+    }
+
+    /// Checks if this step skips text outside the match, which is free when we are searching.
+    fn is_free(&self, ix: Ix, step_type: StepType) -> bool {
+        step_type == StepType::SkipText && (self.is_leading(&ix) || self.is_trailing(&ix))
+    }
+
+    /// Converts a step from `from` to `next` into trace steps, one for each character or failed
+    /// assertion involved.
+    fn trace_steps(&self, from: Ix, next: Ix, step_type: StepType) -> Result<Vec<Step<Match, char>>, Error> {
+        let step = match (step_type.step(), self.pattern.get(from.pattern)) {
+            (_, Some(Flat::Look(look))) if step_type == StepType::FailLook => return Ok(vec![Step::FailLook(*look)]),
+            (Some(step), _) => step,
+            (None, _) => return Ok(vec![]),
+        };
+        let patts = Self::matches(self.pattern.get(from.pattern));
+        let texts = &self.text[from.text..next.text];
+        let steps = match step {
+            // pattern and text whitespace runs can have different lengths, so we pair the extra
+            // text whitespace with the last pattern whitespace
+            Step::Hit(_, _) if patts.len() < texts.len() => {
+                let last = patts.last().cloned();
+                let patts = patts.into_iter().chain(std::iter::repeat_with(|| last.clone()).flatten());
+                patts.zip(texts).map(|(p, t)| Step::Hit(p, *t)).collect()
+            }
+            Step::Hit(_, _)           => patts.into_iter().zip(texts).map(|(p, t)| Step::Hit(p, *t)).collect(),
+            Step::Replace(_, _)       => patts.into_iter().zip(texts).map(|(p, t)| Step::Replace(p, *t)).collect(),
+            Step::Transpose(_, _, _, _) => {
+                let second = Self::matches(self.pattern.get(from.pattern + from.reps));
+                match (&patts[..], &second[..], texts) {
+                    ([p0], [p1], [t0, t1]) => vec![Step::Transpose(p0.clone(), p1.clone(), *t0, *t1)],
+                    _ => return Err(Error::UnexpectedTransposition(format!("{:?}", from))),
+                }
+            }
+            Step::SkipPattern(_)      => patts.into_iter().map(Step::SkipPattern).collect(),
+            Step::SkipText(_)         => texts.iter().map(|t| Step::SkipText(*t)).collect(),
+            Step::FailLook(look)      => vec![Step::FailLook(look)],
+            Step::StartCapture(index) => vec![Step::StartCapture(index)],
+            Step::StopCapture(index)  => vec![Step::StopCapture(index)],
+        };
+        Ok(steps)
+    }
+
+    /// Converts a pattern element into the matches we report in the trace, one for each character.
+    fn matches(flat: Option<&Flat>) -> Vec<Match> {
+        match flat {
+            Some(Flat::Lit(c))         => vec![Match::Lit(*c)],
+            Some(Flat::CaselessLit(c)) => vec![Match::CaselessLit(*c)],
+            Some(Flat::Token(token))   => token.iter().map(|c| Match::Lit(*c)).collect(),
+            Some(Flat::Whitespace(ws)) => ws.iter().map(|c| Match::Lit(*c)).collect(),
+            Some(Flat::Class(c))       => vec![Match::Class(c.clone())],
+            _                          => vec![],
+        }
+    }
+
+    /// Returns the node type for a pattern literal or class that matches the text.
+    fn hit(&self, ix: &Ix) -> NodeType {
+        NodeType::Hit { transpose: self.can_transpose(ix) }
+    }
+
+    /// Returns the node type for a pattern literal or class that does not match the text.
+    ///
+    /// We can only replace single characters, so the text token must be a single character too.
+    fn no_hit(&self, ix: &Ix) -> NodeType {
+        let replace = self.costs.replace.is_some() && self.is_single_char(ix.text);
+        NodeType::NoHit { replace, transpose: self.can_transpose(ix) }
+    }
+
+    fn is_single_char(&self, text_ix: usize) -> bool {
+        text_ix < self.text.len() && self.token_end(text_ix) == text_ix + 1
+    }
+
+    /// Checks if the two pattern literals or classes starting at this index match the next two
+    /// text characters in reverse order.
+    fn can_transpose(&self, ix: &Ix) -> bool {
+        let patts = (self.pattern.get(ix.pattern), self.pattern.get(ix.pattern + ix.reps));
+        let texts = (self.text.get(ix.text), self.text.get(ix.text + 1));
+        match (patts, texts) {
+            ((Some(p0), Some(p1)), (Some(t0), Some(t1))) =>
+                self.costs.transpose.is_some() &&
+                self.is_single_char(ix.text) && self.is_single_char(ix.text + 1) &&
+                char_matches(p0, *t1) && char_matches(p1, *t0),
+            _ => false,
+        }
+    }
+
+    fn look_matches(&self, look: &Look, ix: &Ix) -> bool {
+        let before = ix.text.checked_sub(1).map_or(self.before, |i| self.text.get(i).copied());
+        let after = self.text.get(ix.text).copied().or(self.after);
+        look.matches(before, after)
+    }
+
     fn start(&self) -> Ix {
-        Ix { text: 0, pattern: 0, reps: 1, rep_off: 0 }
+        // an empty pattern starts at the end of the pattern, so there is nothing to start matching
+        let leading = self.search && self.pattern.len() > 0;
+        Ix { text: 0, pattern: 0, reps: 1, rep_off: 0, count: 0, gap: false, leading }
     }
 
     fn end(&self) -> Ix {
@@ -144,25 +476,297 @@ impl Config {
             pattern: self.pattern.len(),
             reps: 1,
             rep_off: 0,
+            count: 0,
+            gap: false,
+            leading: false,
+        }
+    }
+
+    /// Returns the first index and number of repetition copies of the flat pattern element at each
+    /// index in a row of the table, including the end of the pattern.
+    fn blocks(&self) -> Vec<(usize, usize)> {
+        let mut blocks = Vec::with_capacity(self.pattern.len() + 1);
+        let mut reps = 1;
+        while blocks.len() < self.pattern.len() {
+            let start = blocks.len();
+            let next_reps = match self.pattern.get(start) {
+                Some(Flat::RepetitionStart(_, _)) => reps + 1,
+                Some(Flat::RepetitionEnd(_, _)) => reps - 1,
+                _ => reps,
+            };
+            blocks.extend(std::iter::repeat_n((start, reps), reps));
+            reps = next_reps;
+        }
+        blocks.push((self.pattern.len(), reps));
+        blocks
+    }
+
+    /// Returns the index of the first node for each index in a row of the table, including the end
+    /// of the pattern, followed by the number of nodes in a row.
+    ///
+    /// Indices inside a counted repetition, including its start and end, need a node for each
+    /// iteration count we track. Inside nested counted repetitions, we need a node for each
+    /// combination of counts, per [`Ix::count`].
+    fn cell_starts(&self) -> Vec<usize> {
+        let blocks = self.blocks();
+        let mut counts: Vec<usize> = vec![1; blocks.len()];
+        for (ix, (start, reps)) in blocks.iter().copied().enumerate() {
+            match self.pattern.get(ix) {
+                Some(Flat::RepetitionStart(off, bounds)) if ix == start && bounds.is_counted() => {
+                    let factor = self.max_count(bounds).saturating_add(1);
+                    for count in &mut counts[ix..ix + off + reps + 1] {
+                        *count = count.saturating_mul(factor);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let mut cell_starts = Vec::with_capacity(blocks.len() + 1);
+        let mut cells: usize = 0;
+        cell_starts.push(cells);
+        for count in counts {
+            cells = cells.saturating_add(count);
+            cell_starts.push(cells);
+        }
+        // when searching, each row has one more node for the start of the pattern before the match
+        // starts, per [`Ix::leading`]
+        if self.search {
+            cell_starts.push(cells.saturating_add(1));
+        }
+        cell_starts
+    }
+
+    /// Returns the index of this node in its row of the table.
+    fn cell(&self, ix: &Ix) -> usize {
+        cell(&self.cell_starts, ix)
+    }
+
+    /// Returns the number of nodes in each row of the table, ignoring gaps.
+    fn row_cells(&self) -> usize {
+        self.cell_starts[self.cell_starts.len() - 1]
+    }
+
+    /// Returns the most iterations a counted repetition with these bounds can match, if that limit
+    /// can ever stop a match.
+    ///
+    /// Once a repetition reaches its minimum, every iteration but the last has to move through the
+    /// text, so a maximum above the minimum plus the length of the text never applies.
+    fn count_limit(&self, bounds: &Bounds) -> Option<usize> {
+        bounds.maximum.filter(|maximum| *maximum <= bounds.minimum.saturating_add(self.text.len()))
+    }
+
+    /// Returns the highest iteration count we track for a counted repetition with these bounds.
+    ///
+    /// Without a limit, every count from the minimum up behaves the same, so we stop there.
+    fn max_count(&self, bounds: &Bounds) -> usize {
+        self.count_limit(bounds).unwrap_or(bounds.minimum)
+    }
+
+    /// Returns the value of [`Ix::count`] which stands for one iteration of the repetition starting
+    /// or ending at this index, i.e. the number of count combinations of the counted repetitions
+    /// around it.
+    fn count_stride(&self, ix: &Ix, bounds: &Bounds) -> usize {
+        let counts = self.cell_starts[ix.pattern + 1] - self.cell_starts[ix.pattern];
+        counts / self.max_count(bounds).saturating_add(1)
+    }
+
+    /// Returns how many iterations the counted repetition starting or ending at this index matched.
+    fn iterations(&self, ix: &Ix, bounds: &Bounds) -> usize {
+        ix.count / self.count_stride(ix, bounds)
+    }
+
+    /// Returns the count after we leave the repetition starting or ending at this index.
+    fn count_outside(&self, ix: &Ix) -> usize {
+        let bounds = self.bounds(ix);
+        if bounds.is_counted() { ix.count % self.count_stride(ix, &bounds) } else { ix.count }
+    }
+
+    /// Returns the bounds of the repetition starting or ending at this index.
+    fn bounds(&self, ix: &Ix) -> Bounds {
+        match self.pattern.get(ix.pattern) {
+            Some(Flat::RepetitionStart(_, bounds) | Flat::RepetitionEnd(_, bounds)) => *bounds,
+            _ => Bounds::default(),
+        }
+    }
+
+    /// Returns the node type for the start of a repetition, given how many iterations it matched.
+    ///
+    /// Greedy repetitions prefer to start another iteration on ties, while lazy repetitions prefer
+    /// to stop.
+    fn repetition_start(&self, off: usize, bounds: &Bounds, ix: &Ix) -> NodeType {
+        let lazy = !bounds.greedy;
+        if !bounds.is_counted() {
+            return NodeType::RepetitionStart { off, start: true, pass: true, lazy };
+        }
+        let iterations = self.iterations(ix, bounds);
+        let start = self.count_limit(bounds).is_none_or(|limit| iterations < limit);
+        let pass = iterations >= bounds.minimum;
+        NodeType::RepetitionStart { off, start, pass, lazy }
+    }
+
+    /// Returns the node type for the end of a repetition.
+    ///
+    /// We can only end a repetition after an iteration which did not move through the text, as
+    /// repeating it would not get us anywhere. A counted repetition has to restart anyway if it has
+    /// not reached its minimum.
+    fn repetition_end(&self, off: usize, bounds: &Bounds, ix: &Ix) -> NodeType {
+        if ix.can_restart() || bounds.is_counted() && self.iterations(ix, bounds) + 1 < bounds.minimum {
+            NodeType::RepetitionRestart(off)
+        } else {
+            NodeType::RepetitionEnd
+        }
+    }
+
+    /// Returns the fewest and most text characters that the pattern can match exactly before each
+    /// index in a row of the table, where `None` means there is no limit.
+    ///
+    /// This is how far through the text we expect to be if the text matches the pattern.
+    fn widths(&self) -> Vec<(usize, Option<usize>)> {
+        let blocks = self.blocks();
+        let len = self.pattern.len();
+        let mut widths = vec![None; len + 1];
+        widths[0] = Some((0, Some(0)));
+        let merge = |widths: &mut Vec<Option<(usize, Option<usize>)>>, ix: usize, min: usize, max: Option<usize>| {
+            widths[ix] = Some(match widths[ix] {
+                Some((old_min, old_max)) => (old_min.min(min), old_max.zip(max).map(|(a, b)| a.max(b))),
+                None => (min, max),
+            });
+        };
+        let mut ix = 0;
+        while ix < len {
+            let reps = blocks[ix].1;
+            let (min, max) = widths[ix].unwrap_or((0, None));
+            let add = |width: usize| max.map(|max| max + width);
+            match self.pattern.get(ix) {
+                Some(Flat::Lit(_) | Flat::CaselessLit(_) | Flat::Class(_)) =>
+                    merge(&mut widths, ix + reps, min + 1, add(1)),
+                Some(Flat::Token(token)) =>
+                    merge(&mut widths, ix + reps, min + token.len(), add(token.len())),
+                Some(Flat::Whitespace(_)) =>
+                    merge(&mut widths, ix + reps, min, None),
+                Some(Flat::AlternativeLeft(off)) => {
+                    merge(&mut widths, ix + reps, min, max);
+                    merge(&mut widths, ix + off + reps, min, max);
+                }
+                Some(Flat::AlternativeRight(off)) =>
+                    merge(&mut widths, ix + off, min, max),
+                Some(Flat::RepetitionStart(off, bounds)) => {
+                    // if the repetition can match any text, it can match any amount of text, and we
+                    // restart it from here
+                    let max = max.filter(|_| !(ix + reps..ix + off).any(|i| self.matches_text(i)));
+                    widths[ix] = Some((min, max));
+                    merge(&mut widths, ix + reps, min, max);
+                    if bounds.minimum == 0 {
+                        merge(&mut widths, ix + off + reps + 1, min, max);
+                    }
+                }
+                Some(Flat::RepetitionEnd(off, bounds)) => {
+                    // we match the inner pattern at least as many times as the minimum, and at
+                    // least once if we reach the end of the repetition
+                    let start_min = widths[ix - off].map_or(0, |(start_min, _)| start_min);
+                    let inner_min = min.saturating_sub(start_min);
+                    let min = start_min.saturating_add(inner_min.saturating_mul(bounds.minimum.max(1)));
+                    merge(&mut widths, ix + reps, min, max);
+                }
+                _ => merge(&mut widths, ix + reps, min, max),
+            }
+            ix += reps;
+        }
+        blocks.iter().map(|(start, _)| widths[*start].unwrap_or((0, None))).collect()
+    }
+
+    /// Checks if the pattern element at this index matches any text.
+    fn matches_text(&self, pattern_ix: usize) -> bool {
+        matches!(
+            self.pattern.get(pattern_ix),
+            Some(Flat::Lit(_) | Flat::CaselessLit(_) | Flat::Token(_) | Flat::Whitespace(_) | Flat::Class(_))
+        )
+    }
+
+    /// Returns a lower bound on the cost of any match which leaves the band of this width.
+    ///
+    /// Hits never match more text than [`Config::widths`] expects, so a match which ends up after
+    /// the band has skipped more than `width` text characters. Similarly, a match which ends up
+    /// before the band has skipped pattern elements which would have matched more than `width`
+    /// text characters.
+    fn band_escape_cost(&self, width: usize) -> usize {
+        let skip_protected = self.costs.skip_protected.unwrap_or(FORBIDDEN);
+        let text_cost = self.costs.skip_text.min(skip_protected);
+        let text_len = (0..self.text.len()).map(|ix| self.token_end(ix) - ix).max().unwrap_or(1);
+        let pattern_cost = self.costs.skip_lit.min(self.costs.skip_class).min(skip_protected);
+        let pattern_len = (0..self.pattern.len())
+            .map(|ix| match self.pattern.get(ix) {
+                Some(Flat::Token(token)) => token.len(),
+                _ => 1,
+            })
+            .max()
+            .unwrap_or(1);
+        let skips = |len: usize| (width + 1).div_ceil(len);
+        skips(text_len).saturating_mul(text_cost).min(skips(pattern_len).saturating_mul(pattern_cost))
+    }
+
+    /// Checks if we track gaps, i.e. if starting a run of skips costs more than continuing it.
+    fn has_gaps(&self) -> bool {
+        self.costs.gap_open > 0 || self.tie_break.group_skips
+    }
+
+    fn hit_len(&self, ix: Ix) -> usize {
+        match self.pattern.get(ix.pattern) {
+            Some(Flat::Token(token)) => token.len(),
+            Some(Flat::Whitespace(_)) => self.whitespace_end(ix.text) - ix.text,
+            _ => 1,
         }
     }
 
     fn step(&self, ix: Ix, step_type: StepType) -> Ix {
+        let next = self.step_without_gap(ix, step_type);
+        let gap = match step_type {
+            _ if !self.has_gaps() || self.is_free(ix, step_type) => false,
+            StepType::SkipText | StepType::SkipPattern => true,
+            StepType::Hit | StepType::Replace | StepType::Transpose => false,
+            _ => ix.gap,
+        };
+        // only free skips keep us before the start of the match
+        let leading = step_type == StepType::SkipText && self.is_leading(&ix);
+        // there is only one end index, whether we reach it with a skip or not
+        let at_end = next.text == self.text.len() && next.pattern == self.pattern.len();
+        Ix { gap: gap && !at_end, leading, ..next }
+    }
+
+    fn step_without_gap(&self, ix: Ix, step_type: StepType) -> Ix {
         match step_type {
-            StepType::Hit =>
+            StepType::Hit => {
+                let len = self.hit_len(ix);
+                Ix {
+                    pattern: ix.pattern + ix.reps,
+                    text: ix.text + len,
+                    // pattern whitespace can hit no text at all, which does not get us anywhere
+                    rep_off: if len == 0 { ix.rep_off } else { 0 },
+                    ..ix
+                }
+            }
+            StepType::Replace =>
                 Ix {
                     pattern: ix.pattern + ix.reps,
                     text: ix.text + 1,
                     rep_off: 0,
                     ..ix
                 },
+            StepType::Transpose =>
+                Ix {
+                    pattern: ix.pattern + 2 * ix.reps,
+                    text: ix.text + 2,
+                    rep_off: 0,
+                    ..ix
+                },
             StepType::SkipText =>
                 Ix {
-                    text: ix.text + 1,
+                    text: self.token_end(ix.text),
                     rep_off: 0,
                     ..ix
                 },
-            StepType::SkipPattern | StepType::StartGroup | StepType::EndGroup | StepType::StartLeft =>
+            StepType::SkipPattern | StepType::PassLook | StepType::FailLook |
+            StepType::StartGroup(_) | StepType::EndGroup(_) | StepType::StartLeft =>
                 Ix {
                     pattern: ix.pattern + ix.reps,
                     ..ix
@@ -184,48 +788,132 @@ impl Config {
                     rep_off: ix.rep_off + 1,
                     ..ix
                 },
+            // we forget the iterations of a counted repetition once we leave it
             StepType::EndRepetition =>
                 Ix {
                     pattern: ix.pattern + ix.reps,
                     reps: ix.reps - 1,
                     rep_off: ix.rep_off - 1,
+                    count: self.count_outside(&ix),
                     ..ix
                 },
             StepType::PassRepetition(off) =>
                 Ix {
                     pattern: ix.pattern + off + ix.reps + 1,
+                    count: self.count_outside(&ix),
                     ..ix
                 },
-            StepType::RestartRepetition(off) =>
+            StepType::RestartRepetition(off) => {
+                let bounds = self.bounds(&ix);
+                let count = if bounds.is_counted() {
+                    let stride = self.count_stride(&ix, &bounds);
+                    let iterations = (ix.count / stride + 1).min(self.max_count(&bounds));
+                    ix.count % stride + iterations * stride
+                } else {
+                    ix.count
+                };
                 Ix {
                     pattern: ix.pattern - off,
                     reps: ix.reps - 1,
+                    // we only restart without moving through the text to reach the minimum
+                    rep_off: ix.rep_off.saturating_sub(1),
+                    count,
                     ..ix
-                },
+                }
+            }
         }
     }
 }
 
 pub struct State {
     nodes: Vec<Node>,
-    pattern_len: usize,
+    /// The first cell we store in each row, per [`Config::cell`], and the index of its first node.
+    rows: Vec<(usize, usize)>,
+    cell_starts: Vec<usize>,
+    gap_len: usize,
+    /// The text indices inside the band for each pattern index, if we only explore a band.
+    bounds: Option<Vec<RangeInclusive<usize>>>,
 }
 
 impl State {
     fn node(&self, ix: Ix) -> usize {
-        ix.text * self.pattern_len + ix.pattern + ix.rep_off
+        let (first, start) = self.rows[ix.text];
+        let cell = cell(&self.cell_starts, &ix);
+        start + (cell - first) * self.gap_len + ix.gap as usize
     }
 
-    fn new(conf: &Config) -> Self {
+    fn new(conf: &Config, max_nodes: Option<usize>, bounds: Option<Vec<RangeInclusive<usize>>>) -> Result<Self, Error> {
         // we need an extra row/col for indices at the end of pattern and text
         let pattern_len = conf.pattern.len() + 1;
         let text_len = conf.text.len() + 1;
-        let num_nodes = text_len * pattern_len;
+        // if we track gaps, we need separate nodes inside and outside a gap
+        let gap_len = if conf.has_gaps() { 2 } else { 1 };
+        let max_nodes = max_nodes.unwrap_or(usize::MAX);
+        let cell_starts = conf.cell_starts.clone();
+        // we only store the pattern indices between the first and last inside the band
+        let spans = match &bounds {
+            Some(bounds) => {
+                let firsts = Self::first_inside(bounds, 0..pattern_len, text_len);
+                let lasts = Self::first_inside(bounds, (0..pattern_len).rev(), text_len);
+                firsts.into_iter().zip(lasts)
+                    .map(|span| match span {
+                        (Some(first), Some(last)) => (cell_starts[first], cell_starts[last + 1] - cell_starts[first]),
+                        _ => (0, 0),
+                    })
+                    .collect()
+            }
+            None => vec![(0, conf.row_cells()); text_len],
+        };
+        let mut rows = Vec::with_capacity(text_len);
+        let mut num_nodes: usize = 0;
+        for (first, len) in spans {
+            rows.push((first, num_nodes));
+            num_nodes = len.checked_mul(gap_len)
+                .and_then(|nodes| num_nodes.checked_add(nodes))
+                .filter(|nodes| *nodes <= max_nodes)
+                .ok_or(Error::ExceededMaxNodes(max_nodes))?;
+        }
         let nodes = Vec::from_iter((0..num_nodes).into_iter().map(|_| Node::new()));
-        State {
+        Ok(State {
             nodes,
-            pattern_len,
+            rows,
+            cell_starts,
+            gap_len,
+            bounds,
+        })
+    }
+
+    /// Finds the first pattern index, in the given order, whose bounds contain each text index.
+    fn first_inside(
+        bounds: &[RangeInclusive<usize>],
+        order: impl Iterator<Item = usize>,
+        text_len: usize,
+    ) -> Vec<Option<usize>> {
+        let mut firsts = vec![None; text_len];
+        // skips over text indices which already have a pattern index, so we visit each one once
+        let mut next = Vec::from_iter(0..=text_len);
+        let find = |next: &mut Vec<usize>, mut ix: usize| {
+            while next[ix] != ix {
+                next[ix] = next[next[ix]];
+                ix = next[ix];
+            }
+            ix
+        };
+        for pattern_ix in order {
+            let range = &bounds[pattern_ix];
+            let mut text_ix = find(&mut next, (*range.start()).min(text_len));
+            while text_ix < text_len && text_ix <= *range.end() {
+                firsts[text_ix] = Some(pattern_ix);
+                next[text_ix] = text_ix + 1;
+                text_ix = find(&mut next, text_ix + 1);
+            }
         }
+        firsts
+    }
+
+    /// Checks if this node is inside the band, so we can explore it.
+    fn contains(&self, ix: Ix) -> bool {
+        self.bounds.as_ref().is_none_or(|bounds| bounds[ix.pattern + ix.rep_off].contains(&ix.text))
     }
 
     fn get(&self, ix: Ix) -> &Node {
@@ -240,7 +928,9 @@ impl State {
 }
 
 /// Indexes into [`State`].
-/// This struct is a LOT bigger than I initially expected, attempt to reduce size in the future.
+///
+/// This struct is a LOT bigger than I initially expected, so the table does not store any of them.
+/// We only keep them for the nodes we are working on, and recalculate them from the step types.
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug, Default)]
 pub struct Ix {
     /// The index into the flat pattern.
@@ -258,6 +948,25 @@ pub struct Ix {
     /// affects the future score, and so we have a separate score and a separate index for each
     /// repetition depth value.
     pub rep_off: usize,
+    /// This field counts the iterations of the counted repetitions we are inside, if any.
+    ///
+    /// Unlike `*`, a repetition with bounds, e.g. `{2,5}`, needs to know how many times it matched
+    /// its inner pattern, up to [`Config::max_count`]. We have a separate score for each count.
+    /// Inside nested counted repetitions, this combines the count of each one, like the digits of
+    /// a number: the outermost repetition counts in ones, and each repetition inside it counts in
+    /// multiples of the number of count combinations around it, per [`Config::count_stride`].
+    pub count: usize,
+    /// This field tracks whether the last step that moved through the pattern or text was a skip.
+    ///
+    /// When [`Costs::gap_open`] is set, the first skip in a run of consecutive skips costs extra, so
+    /// we need a separate score for nodes inside and outside a gap.
+    pub gap: bool,
+    /// This field tracks whether we are searching, and have not started matching the pattern yet.
+    ///
+    /// Only these nodes can skip text for free. Restarting a repetition takes us back to the start
+    /// of the pattern in the middle of a match, so we need a separate node for the start of the
+    /// pattern before the match starts. Any step other than a free skip starts the match.
+    pub leading: bool,
 }
 
 impl Ix {
@@ -266,228 +975,315 @@ impl Ix {
     }
 }
 
+/// Returns the index of this node in its row of the table, given the first cell for each index in
+/// a row, per [`Config::cell_starts`].
+fn cell(cell_starts: &[usize], ix: &Ix) -> usize {
+    if ix.leading {
+        cell_starts[cell_starts.len() - 2]
+    } else {
+        cell_starts[ix.pattern + ix.rep_off] + ix.count
+    }
+}
+
+/// A node we are still working on, while we calculate the score of each step from it.
 #[derive(Debug)]
-enum LoopState {
-    Down(Down),
-    Back(Back),
+struct Frame {
+    ix: Ix,
+    /// The highest score this node can have while keeping the total cost within the max cost.
+    budget: usize,
+    step_types: StepTypes,
+    /// The index of the step type we are currently trying.
+    current: usize,
+    /// The best score so far, and the index of its step type.
+    best: Option<(usize, usize)>,
 }
 
-impl LoopState {
-    fn current(&self) -> Ix {
-        match self {
-            LoopState::Down(down) => down.current,
-            LoopState::Back(back) => back.current,
+impl Frame {
+    fn new(conf: &Config, ix: Ix, budget: usize) -> Self {
+        let step_types = conf.node_step_types(&ix);
+        Frame { ix, budget, step_types, current: 0, best: None }
+    }
+
+    /// Records the score of the current step, given the score of the node it steps to.
+    ///
+    /// Ties keep the earlier step, so we always pick the first optimal step in the order that
+    /// [`Options::tie_break`] sets.
+    fn update(&mut self, conf: &Config, child_score: usize) {
+        if let Some(step_type) = self.step_types.get(self.current) {
+            let score = child_score.saturating_add(conf.cost(self.ix, step_type));
+            if self.best.is_none_or(|(best_score, _)| score < best_score) {
+                self.best = Some((score, self.current));
+            }
+            self.current += 1;
         }
     }
-}
 
-#[derive(Debug)]
-struct Down {
-    parent: Ix,
-    current: Ix,
+    /// Returns the finished node once we have tried every step type.
+    fn finish(&self, conf: &Config) -> Result<Node, Error> {
+        match self.best {
+            Some((score, step)) => Ok(Node::finished(score, step, self.budget)),
+            // the end node is the only node without any steps
+            None if self.ix == conf.end() => Ok(Node::finished(0, 0, self.budget)),
+            None => Err(Error::NoNodeType(format!("{:?}", self.ix))),
+        }
+    }
 }
 
-#[derive(Debug)]
-struct Back {
-    current: Ix,
-    child: Ix,
+/// What we know about a node in the table.
+///
+/// We store a node for every pair of pattern and text indices, so we keep these as small as
+/// possible. While we work on a node, everything else we need lives in its [`Frame`], and once the
+/// node is finished, we only store its score, and which of its step types is optimal.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct Node {
+    /// The optimal score if the node is done, or the budget we calculated it with if the score is
+    /// over budget.
+    value: usize,
+    /// The index of the optimal step type.
+    step: u8,
+    status: Status,
 }
 
-// TODO make a better Node type
-//
-// Calculate_optimal_path (originally called solve_ix) used to store a lot of state on the stack:
-// the parent node, our progress through the possible step types, the optimal score, etc. The
-// node was a simple enum which was either Ready, Working, or Done. Only the Done value had any
-// fields, and it was never mutated.
-//
-// Once we began to run out of stack space for mid-sized use-cases, we transferred all of that
-// state into the heap by adding it to this Node struct. Much of this information is mutated as we
-// try out each possible step type.
-//
-// I had a lot of trouble implementing this expanded node. Solve loops over my table of node
-// values, taking a mutable reference to a single node in each iteration. My code originally
-// pattern matched on the Node enum, and called methods on inner types which could only be accessed
-// when node had the right case. But I struggled to do this and satisfy rust's borrow checker.
-//
-// For now, I've abandonded pattern matching and type safety, and implemented rust as an abstract
-// data type. The node still has three states: Ready, Working, and Done, but they aren't reflected
-// in rust's type system. Instead, Node methods return errors if they are called when the node is
-// in the wrong state.
-//
-// The three states are a bit implicit in the Node structure. They are driven by current. Current
-// changes from 0..=step_types.len()+1 over the life of the Node:
-//
-// 1. A node is Ready if current == 0
-// 2. A node is Working if 1 >= current >= step_types.len()
-// 3. A node is Done if current == step_types.len() + 1
-//
-// When a node is working, the current step type being attempted is step_types[current-1].
-//
-// When a node has processed at least one node (current >= 2), score/step_type/next record the
-// optimal choice among step_types[0..current-1]. This means those fields are optimal when a Node
-// is Done.
-//
-// I'd like to return to this Node when I'm more comfortable working with rust, and do a better job
-// implementing it.
-
-#[derive(Clone, Eq, PartialEq, Debug)]
-pub struct Node {
-    current: usize,
-    parent: Ix,
-    score: usize,
-    step_type: StepType,
-    next: Ix,
-    step_types: Vec<StepType>,
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Status {
+    Ready,
+    Working,
+    Done,
+    /// The score is higher than the node's budget, so we do not know what it is.
+    OverBudget,
 }
 
 impl Node {
     pub fn new() -> Self {
-        Self {
-            current: 0,
-            parent: Default::default(),
-            score: 0,
-            step_type: StepType::Hit,
-            next: Default::default(),
-            step_types: vec![],
-        }
+        Node { value: 0, step: 0, status: Status::Ready }
     }
 
-    fn is_ready(&self) -> bool {
-        self.current == 0
+    fn working() -> Self {
+        Node { status: Status::Working, ..Node::new() }
     }
 
-    fn is_working(&self) -> bool {
-        self.current > 0 && self.current <= self.step_types.len()
+    fn finished(score: usize, step: usize, budget: usize) -> Self {
+        // step types have a fixed maximum length, so the index always fits
+        let step = step as u8;
+        if score > budget {
+            Node { value: budget, step, status: Status::OverBudget }
+        } else {
+            Node { value: score, step, status: Status::Done }
+        }
     }
 
-    fn is_done(&self) -> bool {
-        self.current > self.step_types.len()
+    fn is_finished(&self) -> bool {
+        matches!(self.status, Status::Done | Status::OverBudget)
     }
 
-    fn current_step_type(&self) -> Result<StepType, Error> {
-        if self.is_working() {
-            Ok(self.step_types[self.current - 1])
-        } else {
-            Err(Error::CannotGetNodeField("current_step_type", "working"))
+    /// Returns the optimal score, or a forbidden score if we only know it is over budget.
+    fn score(&self) -> usize {
+        match self.status {
+            Status::Done => self.value,
+            _ => FORBIDDEN,
         }
     }
 
-    fn done_info(&self) -> Result<(usize, StepType, Ix), Error> {
-        if self.is_done() {
-            Ok((self.score, self.step_type, self.next))
-        } else {
-            Err(Error::CannotGetNodeField("score/step_type/next", "done"))
-        }
+    /// Checks if we cut this node short with a smaller budget than this one.
+    fn is_over_budget(&self, budget: usize) -> bool {
+        self.status == Status::OverBudget && budget > self.value
     }
+}
 
-    fn initialise(&mut self, end_ix: Ix, parent_ix: Ix, ix: Ix, opt_node_type: Option<NodeType>) -> Result<(), Error>{
-        if self.is_ready() {
-            match opt_node_type {
-                Some(node_type) => {
-                    let step_types = Vec::from(node_type.step_types());
-                    self.parent = parent_ix;
-                    self.current += 1;
-                    self.step_types = step_types;
-                    Ok(())
-                }
-                None if ix == end_ix => { // end_ix: insert dummy done value
-                    self.parent = parent_ix;
-                    self.current += 1;
-                    Ok(())
-                }
-                None => {
-                    Err(Error::NoNodeType(format!("{:?}", ix)))
-                }
-            }
-        } else {
-            Err(Error::CannotInitialiseNode(format!("{:?}", ix)))
-        }
-    }
-
-    fn update(&mut self, new_child: Ix, ix: Ix, new_score: usize) -> Result<Ix, Error> {
-        if self.is_working() {
-            let parent_ix = self.parent;
-            let current_step_type = self.current_step_type()?;
-            let new_score = new_score + current_step_type.cost();
-            if self.current <= 1 || new_score < self.score {
-                self.step_type = current_step_type;
-                self.score = new_score;
-                self.next = new_child;
-                self.current += 1;
-            } else {
-                self.current += 1;
-            }
-            Ok(parent_ix)
-        } else {
-            Err(Error::CannotUpdateNode(format!("{:?}", ix)))
-        }
-   }
+/// Checks if a single character pattern element, i.e. a literal or class, matches this character.
+fn char_matches(flat: &Flat, c: char) -> bool {
+    match flat {
+        Flat::Lit(p) => *p == c,
+        Flat::CaselessLit(p) => eq_ignore_case(*p, c),
+        Flat::Class(class) => class.matches(c),
+        _ => false,
+    }
+}
+
+fn eq_ignore_case(a: char, b: char) -> bool {
+    a == b || a.to_lowercase().eq(b.to_lowercase()) || a.to_uppercase().eq(b.to_uppercase())
 }
 
 #[derive(Copy, Clone, Eq, Hash, PartialEq, Debug)]
 pub enum NodeType {
     FinishedPattern,
     FinishedText,
-    Hit,
-    NoHit,
-    StartGroup,
-    EndGroup,
+    /// The pattern matches no text at the end of the text, which only pattern whitespace can do.
+    HitFinishedText,
+    /// The pattern matches the text. We may also be able to transpose the next two characters.
+    Hit { transpose: bool },
+    /// The pattern does not match the text. We may be able to replace the pattern with the text,
+    /// or transpose the next two characters.
+    NoHit { replace: bool, transpose: bool },
+    PassedLook,
+    FailedLook,
+    PassedLookFinishedText,
+    FailedLookFinishedText,
+    StartGroup(usize),
+    EndGroup(usize),
     AlternativeLeft(usize),
     AlternativeRight(usize),
-    RepetitionStart(usize),
+    /// The start of a repetition, where we may be able to start another iteration, or pass the
+    /// rest of the repetition, depending on its bounds.
+    RepetitionStart { off: usize, start: bool, pass: bool, lazy: bool },
     RepetitionRestart(usize),
     RepetitionEnd,
 }
 
 impl NodeType {
-    fn get(opt_flat: Option<&Flat>, opt_text: Option<&char>, ix: &Ix) -> Option<Self> {
+    fn get(conf: &Config, ix: &Ix) -> Option<Self> {
         // TODO this is surprisingly hard to follow for something conceptually simple. Can I make it nicer?
+        let (opt_flat, opt_text) = conf.get(*ix);
         match opt_flat {
             None if opt_text == None => None,
             None => Some(NodeType::FinishedPattern),
             Some(flat) => Some(match flat {
-                Flat::Lit(c) if opt_text == Some(c) => NodeType::Hit,
+                Flat::Lit(c) if opt_text == Some(c) && conf.lit_fits(ix) => conf.hit(ix),
                 Flat::Lit(_) if opt_text == None => NodeType::FinishedText,
-                Flat::Lit(_) => NodeType::NoHit,
-                Flat::Class(class) if opt_text.map_or(false, |t| class.matches(*t)) => NodeType::Hit,
+                Flat::Lit(_) => conf.no_hit(ix),
+                Flat::CaselessLit(c) if opt_text.is_some_and(|t| eq_ignore_case(*c, *t)) && conf.lit_fits(ix) => conf.hit(ix),
+                Flat::CaselessLit(_) if opt_text.is_none() => NodeType::FinishedText,
+                Flat::CaselessLit(_) => conf.no_hit(ix),
+                Flat::Token(_) if opt_text.is_none() => NodeType::FinishedText,
+                Flat::Token(token) if conf.token_matches(token, ix) => NodeType::Hit { transpose: false },
+                Flat::Token(_) => NodeType::NoHit { replace: false, transpose: false },
+                Flat::Whitespace(_) if opt_text.is_none() && conf.after_whitespace(ix) => NodeType::HitFinishedText,
+                Flat::Whitespace(_) if opt_text.is_none() => NodeType::FinishedText,
+                Flat::Whitespace(_) if opt_text.is_some_and(|t| t.is_whitespace()) || conf.after_whitespace(ix) =>
+                    NodeType::Hit { transpose: false },
+                Flat::Whitespace(_) => NodeType::NoHit { replace: false, transpose: false },
+                Flat::Class(class) if opt_text.map_or(false, |t| class.matches(*t)) => conf.hit(ix),
                 Flat::Class(_) if opt_text == None => NodeType::FinishedText,
-                Flat::Class(_) => NodeType::NoHit,
-                Flat::GroupStart => NodeType::StartGroup,
-                Flat::GroupEnd => NodeType::EndGroup,
+                Flat::Class(_) => conf.no_hit(ix),
+                Flat::Look(look) => match (conf.look_matches(look, ix), opt_text) {
+                    (true, Some(_)) => NodeType::PassedLook,
+                    (false, Some(_)) => NodeType::FailedLook,
+                    (true, None) => NodeType::PassedLookFinishedText,
+                    (false, None) => NodeType::FailedLookFinishedText,
+                }
+                Flat::GroupStart(group) => NodeType::StartGroup(group.index),
+                Flat::GroupEnd(index) => NodeType::EndGroup(*index),
                 Flat::AlternativeLeft(off) => NodeType::AlternativeLeft(*off),
                 Flat::AlternativeRight(off) => NodeType::AlternativeRight(*off),
-                Flat::RepetitionStart(off) => NodeType::RepetitionStart(*off),
-                Flat::RepetitionEnd(off) if ix.can_restart() => NodeType::RepetitionRestart(*off),
-                Flat::RepetitionEnd(_) => NodeType::RepetitionEnd,
+                Flat::RepetitionStart(off, bounds) => conf.repetition_start(*off, bounds, ix),
+                Flat::RepetitionEnd(off, bounds) => conf.repetition_end(*off, bounds, ix),
             })
         }
     }
 
-    fn step_types(&self) -> NonEmpty<StepType> {
+    fn step_types(&self) -> StepTypes {
         use StepType::*;
         match self {
-            Self::FinishedPattern => nonempty![SkipText],
-            Self::FinishedText => nonempty![SkipPattern],
-            Self::Hit => nonempty![Hit, SkipPattern, SkipText],
-            Self::NoHit => nonempty![SkipPattern, SkipText],
-            Self::StartGroup => nonempty![StartGroup],
-            Self::EndGroup => nonempty![EndGroup],
-            Self::AlternativeLeft(off) => nonempty![StartLeft, StartRight(*off)],
-            Self::AlternativeRight(off) => nonempty![PassRight(*off)],
-            Self::RepetitionStart(off) => nonempty![StartRepetition, PassRepetition(*off)],
-            Self::RepetitionRestart(off) => nonempty![RestartRepetition(*off)],
-            Self::RepetitionEnd => nonempty![EndRepetition],
+            Self::FinishedPattern => StepTypes::new(&[SkipText]),
+            Self::FinishedText => StepTypes::new(&[SkipPattern]),
+            Self::HitFinishedText => StepTypes::new(&[Hit, SkipPattern]),
+            Self::Hit { transpose } => {
+                let mut steps = StepTypes::new(&[Hit, SkipPattern, SkipText]);
+                if *transpose { steps.push(Transpose); }
+                steps
+            }
+            Self::NoHit { replace, transpose } => {
+                let mut steps = if *replace {
+                    StepTypes::new(&[Replace, SkipPattern, SkipText])
+                } else {
+                    StepTypes::new(&[SkipPattern, SkipText])
+                };
+                if *transpose { steps.push(Transpose); }
+                steps
+            }
+            Self::PassedLook => StepTypes::new(&[PassLook, SkipText]),
+            Self::FailedLook => StepTypes::new(&[FailLook, SkipText]),
+            Self::PassedLookFinishedText => StepTypes::new(&[PassLook]),
+            Self::FailedLookFinishedText => StepTypes::new(&[FailLook]),
+            Self::StartGroup(index) => StepTypes::new(&[StartGroup(*index)]),
+            Self::EndGroup(index) => StepTypes::new(&[EndGroup(*index)]),
+            Self::AlternativeLeft(off) => StepTypes::new(&[StartLeft, StartRight(*off)]),
+            Self::AlternativeRight(off) => StepTypes::new(&[PassRight(*off)]),
+            Self::RepetitionStart { off, start, pass, lazy } => {
+                let mut steps = StepTypes::default();
+                if *pass && *lazy { steps.push(PassRepetition(*off)); }
+                if *start { steps.push(StartRepetition); }
+                if *pass && !*lazy { steps.push(PassRepetition(*off)); }
+                steps
+            }
+            Self::RepetitionRestart(off) => StepTypes::new(&[RestartRepetition(*off)]),
+            Self::RepetitionEnd => StepTypes::new(&[EndRepetition]),
         }
     }
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+/// The steps we can take from a node, in the order we try them.
+///
+/// A node has at most four step types, so we store them inline rather than allocating a vector
+/// for every node we visit.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct StepTypes {
+    step_types: [Option<StepType>; 4],
+}
+
+impl StepTypes {
+    fn new(step_types: &[StepType]) -> Self {
+        let mut result = StepTypes::default();
+        for step_type in step_types {
+            result.push(*step_type);
+        }
+        result
+    }
+
+    fn push(&mut self, step_type: StepType) {
+        let len = self.len();
+        self.step_types[len] = Some(step_type);
+    }
+
+    fn len(&self) -> usize {
+        self.step_types.iter().take_while(|step_type| step_type.is_some()).count()
+    }
+
+    fn get(&self, index: usize) -> Option<StepType> {
+        self.step_types.get(index).copied().flatten()
+    }
+
+    fn contains(&self, step_type: StepType) -> bool {
+        self.step_types.contains(&Some(step_type))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = StepType> + '_ {
+        self.step_types.iter().map_while(|step_type| *step_type)
+    }
+
+    /// Moves the step types matching the predicate in front of the others, keeping their order.
+    fn move_to_front(&mut self, predicate: impl Fn(&StepType) -> bool) {
+        let mut result = StepTypes::default();
+        for step_type in self.iter().filter(|step_type| predicate(step_type)) {
+            result.push(step_type);
+        }
+        for step_type in self.iter().filter(|step_type| !predicate(step_type)) {
+            result.push(step_type);
+        }
+        *self = result;
+    }
+
+    /// Swaps the first step types matching each predicate, if there are both.
+    fn swap(&mut self, first: impl Fn(&StepType) -> bool, second: impl Fn(&StepType) -> bool) {
+        let a = self.iter().position(|step_type| first(&step_type));
+        let b = self.iter().position(|step_type| second(&step_type));
+        if let (Some(a), Some(b)) = (a, b) {
+            self.step_types.swap(a, b);
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 pub enum StepType {
     SkipText,
     SkipPattern,
     Hit,
-    StartGroup,
-    EndGroup,
+    Replace,
+    Transpose,
+    PassLook,
+    FailLook,
+    StartGroup(usize),
+    EndGroup(usize),
     StartLeft,
     StartRight(usize),
     PassRight(usize),
@@ -498,22 +1294,48 @@ pub enum StepType {
 }
 
 impl StepType {
-    fn cost(&self) -> usize {
-        match self {
-            Self::SkipPattern => 1,
-            Self::SkipText    => 1,
-            _                 => 0,
+    /// Returns the cost of this step, where `text` is the token starting at the current text index.
+    fn cost(&self, costs: &Costs, patt: Option<&Flat>, text: &[char]) -> usize {
+        let is_protected = |c: &char| costs.protected.contains(c);
+        let protected_cost = costs.skip_protected.unwrap_or(FORBIDDEN);
+        match (self, patt) {
+            (Self::Hit, Some(Flat::Class(_)))                               => costs.hit_class,
+            (Self::Hit, Some(Flat::Whitespace(_)))                          => 0,
+            (Self::Hit, Some(Flat::CaselessLit(c))) if text.first() != Some(c) => costs.hit_case,
+            (Self::Hit, _)                                                  => costs.hit_lit,
+            (Self::SkipPattern, Some(Flat::Class(_)))                       => costs.skip_class,
+            (Self::SkipPattern, Some(Flat::Lit(c) | Flat::CaselessLit(c)))
+                if is_protected(c)                                          => protected_cost,
+            (Self::SkipPattern, Some(Flat::Token(t) | Flat::Whitespace(t)))
+                if t.iter().any(is_protected)                               => protected_cost,
+            (Self::SkipPattern, _)                                          => costs.skip_lit,
+            (Self::SkipText, _) if text.iter().any(is_protected)            => protected_cost,
+            (Self::SkipText, _)                                             => costs.skip_text,
+            (Self::Replace, Some(Flat::Lit(c) | Flat::CaselessLit(c)))
+                if is_protected(c)                                          => protected_cost,
+            (Self::Replace, _) if text.iter().any(is_protected)             => protected_cost,
+            (Self::Replace, _)                                              => costs.replace.unwrap_or(FORBIDDEN),
+            (Self::Transpose, _)                                            => costs.transpose.unwrap_or(FORBIDDEN),
+            (Self::FailLook, _)                                             => costs.fail_look,
+            _                                                               => 0,
         }
     }
 
+    /// Checks if this step adds anything to the trace.
+    fn has_trace(&self) -> bool {
+        self.step().is_some() || *self == Self::FailLook
+    }
+
     fn step(&self) -> Option<Step<(),()>> {
         match self {
-            Self::Hit         => Some(Step::Hit((), ())),
-            Self::SkipPattern => Some(Step::SkipPattern(())),
-            Self::SkipText    => Some(Step::SkipText(())),
-            Self::StartGroup  => Some(Step::StartCapture),
-            Self::EndGroup    => Some(Step::StopCapture),
-            _                 => None,
+            Self::Hit               => Some(Step::Hit((), ())),
+            Self::Replace           => Some(Step::Replace((), ())),
+            Self::Transpose         => Some(Step::Transpose((), (), (), ())),
+            Self::SkipPattern       => Some(Step::SkipPattern(())),
+            Self::SkipText          => Some(Step::SkipText(())),
+            Self::StartGroup(index) => Some(Step::StartCapture(*index)),
+            Self::EndGroup(index)   => Some(Step::StopCapture(*index)),
+            _                       => None,
         }
     }
 }
@@ -524,21 +1346,35 @@ pub mod test_logic {
     use crate::test_cases::TestCase;
 
     pub fn test_solve(test_case: TestCase) {
+        test_solve_with(test_case, &Options::default());
+    }
+
+    pub fn test_solve_with(test_case: TestCase, options: &Options) {
         let desugared = test_case.pattern.desugar();
-        let actual = solve(&desugared, &test_case.text).unwrap();
+        let actual = solve(&desugared, &test_case.text, options).unwrap();
         assert_eq!(test_case.score, actual.score);
         assert_eq!(test_case.trace, actual.trace);
     }
 }
 #[cfg(test)]
 mod tests {
-    use super::test_logic;
-    use crate::test_cases::TestCase;
+    use super::{Node, solve, test_logic};
+    use crate::{Band, Bounds, Costs, ElementCore, Limits, Match, Options, Pattern, Solver, Step, TieBreak, Tokens};
+    use crate::capture_output::Captures;
+    use crate::diff_output::DiffOutput;
+    use crate::error::Error;
+    use crate::regex_pattern::parse_pattern;
+    use crate::test_cases::{TestCase, lit, lits, look, patt_look, pattern, text, words};
+    use proptest::prelude::*;
+    use std::ops::Range;
     use test_case::test_case;
 
     #[test_case(TestCase::match_empty())]
     #[test_case(TestCase::fail_empty_1())]
     #[test_case(TestCase::fail_empty_2())]
+    #[test_case(TestCase::match_empty_capture())]
+    #[test_case(TestCase::match_empty_alternative())]
+    #[test_case(TestCase::fail_empty_repetition())]
     #[test_case(TestCase::match_lit_1())]
     #[test_case(TestCase::match_lit_2())]
     #[test_case(TestCase::fail_lit_1())]
@@ -547,7 +1383,11 @@ mod tests {
     #[test_case(TestCase::match_class_1())]
     #[test_case(TestCase::match_class_2())]
     #[test_case(TestCase::match_class_3())]
+    #[test_case(TestCase::match_class_4())]
     #[test_case(TestCase::fail_class_1())]
+    #[test_case(TestCase::fail_class_2())]
+    #[test_case(TestCase::match_unicode_1())]
+    #[test_case(TestCase::fail_unicode_1())]
     #[test_case(TestCase::match_alternative_1())]
     #[test_case(TestCase::match_alternative_2())]
     #[test_case(TestCase::match_alternative_3())]
@@ -560,7 +1400,443 @@ mod tests {
     #[test_case(TestCase::fail_repetition_1())]
     #[test_case(TestCase::fail_repetition_2())]
     #[test_case(TestCase::fail_repetition_3())]
+    #[test_case(TestCase::match_look_1())]
+    #[test_case(TestCase::match_look_2())]
+    #[test_case(TestCase::match_look_3())]
+    #[test_case(TestCase::fail_look_1())]
+    #[test_case(TestCase::fail_look_2())]
+    #[test_case(TestCase::match_capture_1())]
+    #[test_case(TestCase::match_capture_2())]
+    #[test_case(TestCase::match_capture_3())]
+    #[test_case(TestCase::match_named_capture_1())]
+    #[test_case(TestCase::fail_capture_1())]
+    #[test_case(TestCase::match_words_1())]
+    #[test_case(TestCase::fail_words_1())]
+    #[test_case(TestCase::fail_words_2())]
+    #[test_case(TestCase::fail_lines_1())]
+    #[test_case(TestCase::fail_lines_2())]
+    #[test_case(TestCase::match_caseless_1())]
+    #[test_case(TestCase::fail_caseless_1())]
     fn test_solve(test: TestCase) {
         test_logic::test_solve(test);
     }
+
+    #[test_case(TestCase::match_costs_hit_class(), Costs { hit_class: 1, ..Costs::default() })]
+    #[test_case(TestCase::fail_costs_skip_class(), Costs { skip_lit: 2, ..Costs::default() })]
+    #[test_case(TestCase::fail_costs_skip_text(), Costs { skip_text: 3, ..Costs::default() })]
+    #[test_case(TestCase::fail_costs_look(), Costs { fail_look: 5, ..Costs::default() })]
+    #[test_case(TestCase::fail_protected_1(), protected(&['"'], None))]
+    #[test_case(TestCase::fail_protected_2(), protected(&['\n'], Some(5)))]
+    #[test_case(TestCase::fail_costs_hit_case(), Costs { skip_lit: 2, skip_text: 2, hit_case: 1, ..Costs::default() })]
+    #[test_case(TestCase::fail_replace_1(), Costs { replace: Some(1), ..Costs::default() })]
+    #[test_case(TestCase::fail_replace_2(), Costs { replace: Some(1), ..Costs::default() })]
+    #[test_case(TestCase::fail_replace_3(), Costs { replace: Some(1), ..Costs::default() })]
+    #[test_case(TestCase::fail_transpose_1(), Costs { transpose: Some(1), ..Costs::default() })]
+    #[test_case(TestCase::fail_transpose_2(), Costs { transpose: Some(1), ..Costs::default() })]
+    #[test_case(TestCase::fail_transpose_3(), Costs { transpose: Some(1), ..Costs::default() })]
+    #[test_case(TestCase::fail_gap_1(), Costs { gap_open: 2, ..Costs::default() })]
+    #[test_case(TestCase::fail_gap_2(), Costs { gap_open: 2, ..Costs::default() })]
+    #[test_case(TestCase::fail_gap_3(), Costs { gap_open: 1, ..Costs::default() })]
+    fn test_solve_costs(test: TestCase, costs: Costs) {
+        test_logic::test_solve_with(test, &Options { costs, ..Options::default() });
+    }
+
+    #[test_case(TestCase::match_whitespace_1())]
+    #[test_case(TestCase::match_whitespace_2())]
+    #[test_case(TestCase::match_whitespace_3())]
+    #[test_case(TestCase::match_whitespace_4())]
+    #[test_case(TestCase::fail_whitespace_1())]
+    fn test_solve_ignore_whitespace(test: TestCase) {
+        test_logic::test_solve_with(test, &Options { ignore_whitespace: true, ..Options::default() });
+    }
+
+    #[test_case("to deal (in )  the Software", "to deal\nin  the Software", 0)]
+    #[test_case("a( )*b", "a  b", 0)]
+    #[test_case("a( ){2,3}b", "a  b", 0)]
+    #[test_case("a (b)", "ab", 1)]
+    fn test_solve_ignore_whitespace_split_run(pattern_str: &str, text_str: &str, score: usize) {
+        // a capture group splits these runs of pattern whitespace, so the runs after the first one
+        // match no text, but the first one still needs some text whitespace
+        let pattern = parse_pattern(pattern_str, false).unwrap().desugar();
+        let options = Options { ignore_whitespace: true, ..Options::default() };
+        let actual = solve(&pattern, &text(text_str), &options).unwrap();
+        assert_eq!(score, actual.score);
+    }
+
+    #[test_case(TestCase::match_search_1(), 2..5)]
+    #[test_case(TestCase::match_search_2(), 0..2)]
+    #[test_case(TestCase::match_search_3(), 0..0)]
+    #[test_case(TestCase::fail_search_1(), 3..7)]
+    fn test_solve_search(test: TestCase, range: Range<usize>) {
+        let options = Options { search: true, ..Options::default() };
+        let actual = solve(&test.pattern.desugar(), &test.text, &options).unwrap();
+        assert_eq!(test.score, actual.score);
+        assert_eq!(test.trace, actual.trace);
+        assert_eq!(range, actual.range);
+    }
+
+    // restarting a repetition in the middle of a match does not make the text before it free
+    #[test_case("(?:ab)*c", "xxabZZZZZZZZZcyy", 13..14, "c")]
+    #[test_case("(ab)*c", "xxabZZZZcyy", 8..9, "c")]
+    fn test_solve_search_repetition(pattern_str: &str, text_str: &str, range: Range<usize>, diff: &str) {
+        let pattern = parse_pattern(pattern_str, false).unwrap().desugar();
+        for solver in [Solver::Table, Solver::LinearSpace] {
+            let options = Options { search: true, solver, ..Options::default() };
+            let actual = solve(&pattern, &text(text_str), &options).unwrap();
+            assert_eq!(range, actual.range);
+            assert_eq!(diff, DiffOutput::new(&actual.score, &actual.trace).to_string());
+            let captures = Captures::new_at(&actual.trace, &pattern.groups(), actual.range.start);
+            assert!(captures.groups.iter().flatten().all(|capture| capture.start >= range.start));
+        }
+    }
+
+    #[test_case(TestCase::fail_lit_3())]
+    #[test_case(TestCase::fail_class_2())]
+    #[test_case(TestCase::fail_alternative_1())]
+    #[test_case(TestCase::fail_repetition_1())]
+    #[test_case(TestCase::fail_repetition_3())]
+    #[test_case(TestCase::fail_look_2())]
+    #[test_case(TestCase::fail_capture_1())]
+    #[test_case(TestCase::fail_words_2())]
+    fn test_solve_max_cost(test: TestCase) {
+        let within = Options { max_cost: Some(test.score), ..Options::default() };
+        let actual = solve(&test.pattern.desugar(), &test.text, &within).unwrap();
+        assert_eq!(test.score, actual.score);
+        assert_eq!(test.trace, actual.trace);
+
+        let max_cost = test.score - 1;
+        let below = Options { max_cost: Some(max_cost), ..Options::default() };
+        let actual = solve(&test.pattern.desugar(), &test.text, &below);
+        assert!(matches!(actual, Err(Error::NoMatchWithinMaxCost(m)) if m == max_cost));
+    }
+
+    #[test]
+    fn test_solve_max_nodes() {
+        let test = TestCase::fail_lit_3();
+        // a row and a column for each of the 5 pattern and text characters, plus one for the ends
+        let nodes = 6 * 6;
+
+        let options = Options { limits: Limits { max_nodes: Some(nodes), ..Limits::default() }, ..Options::default() };
+        let actual = solve(&test.pattern.desugar(), &test.text, &options).unwrap();
+        assert_eq!(test.score, actual.score);
+
+        let options = Options { limits: Limits { max_nodes: Some(nodes - 1), ..Limits::default() }, ..Options::default() };
+        let actual = solve(&test.pattern.desugar(), &test.text, &options);
+        assert!(matches!(actual, Err(Error::ExceededMaxNodes(m)) if m == nodes - 1));
+    }
+
+    #[test]
+    fn test_solve_large_bound() {
+        // expanding the bounds would need a copy of the class for each of the 1000 iterations
+        let pattern = parse_pattern("v[0-9]{1,1000}", false).unwrap().desugar();
+        let options = Options { limits: Limits { max_nodes: Some(100), ..Limits::default() }, ..Options::default() };
+        let actual = solve(&pattern, &text("v12345"), &options).unwrap();
+        assert_eq!(0, actual.score);
+        assert_eq!(6, actual.trace.len());
+
+        let actual = solve(&pattern, &text("v"), &options).unwrap();
+        assert_eq!(Costs::default().skip_class, actual.score);
+    }
+
+    #[test]
+    fn test_solve_bound_shorter_than_text() {
+        // once the text is longer than the bound, we track every count, so each row needs a node
+        // for the v, the start, both copies of the class and both copies of the end for each of the
+        // 11 counts, and the end of the pattern
+        let pattern = parse_pattern("v[0-9]{1,10}", false).unwrap().desugar();
+        let text = text(&format!("v{}", "1234567890".repeat(3)));
+        let nodes = (1 + 5 * 11 + 1) * 32;
+
+        let options = Options { limits: Limits { max_nodes: Some(nodes), ..Limits::default() }, ..Options::default() };
+        let actual = solve(&pattern, &text, &options).unwrap();
+        assert_eq!(20, actual.score);
+
+        let options = Options { limits: Limits { max_nodes: Some(nodes - 1), ..Limits::default() }, ..Options::default() };
+        let actual = solve(&pattern, &text, &options);
+        assert!(matches!(actual, Err(Error::ExceededMaxNodes(m)) if m == nodes - 1));
+    }
+
+    #[test]
+    fn test_solve_nested_large_bound() {
+        // we count the iterations of both repetitions, rather than expanding the inner one
+        let pattern = parse_pattern(r"([0-9]{1,1000}\.){1,4}", false).unwrap().desugar();
+        let options = Options { limits: Limits { max_nodes: Some(2000), ..Limits::default() }, ..Options::default() };
+        let actual = solve(&pattern, &text("192.168.1.1."), &options).unwrap();
+        assert_eq!(0, actual.score);
+    }
+
+    #[test_case("a{3}", "aa", 1)]
+    #[test_case("a{3}", "aaaa", 1)]
+    #[test_case("a{2,3}", "aaaa", 1)]
+    #[test_case("(a?){3}", "a", 0)]
+    #[test_case("(ab){2,}", "abab", 0)]
+    #[test_case("(ab){2,}", "ab", 2)]
+    #[test_case("(a{2}b){2}", "aabab", 1)]
+    #[test_case("(a|b){0}", "a", 1)]
+    fn test_solve_bounded_repetition(pattern_str: &str, text_str: &str, score: usize) {
+        let pattern = parse_pattern(pattern_str, false).unwrap().desugar();
+        let actual = solve(&pattern, &text(text_str), &Options::default()).unwrap();
+        assert_eq!(score, actual.score);
+    }
+
+    // restarting a counted repetition is part of the match, so it cannot skip text for free
+    #[test_case("(?:cb){2}", "cabc", 0..4, "c{+a+}bc[-b-]")]
+    #[test_case("(?:ab){2}", "xxabZabyy", 2..7, "ab{+Z+}ab")]
+    #[test_case("(?:ab){3}c", "xababZabcy", 1..9, "abab{+Z+}abc")]
+    fn test_solve_bounded_repetition_search(pattern_str: &str, text_str: &str, range: Range<usize>, diff: &str) {
+        let pattern = parse_pattern(pattern_str, false).unwrap().desugar();
+        for solver in [Solver::Table, Solver::LinearSpace] {
+            let options = Options { search: true, solver, ..Options::default() };
+            let actual = solve(&pattern, &text(text_str), &options).unwrap();
+            assert_eq!(range, actual.range);
+            assert_eq!(diff, DiffOutput::new(&actual.score, &actual.trace).to_string());
+        }
+    }
+
+    #[test]
+    fn test_node_size() {
+        // we store a node for every pair of pattern and text indices, so keep them small
+        assert!(std::mem::size_of::<Node>() <= 16);
+    }
+
+    #[test]
+    fn test_solve_max_steps() {
+        let test = TestCase::fail_lit_3();
+        let options = Options { limits: Limits { max_steps: 5, ..Limits::default() }, ..Options::default() };
+        let actual = solve(&test.pattern.desugar(), &test.text, &options);
+        assert!(matches!(actual, Err(Error::ExceededMaxSteps(5))));
+    }
+
+    #[test_case(TestCase::fail_lit_3())]
+    #[test_case(TestCase::fail_class_2())]
+    #[test_case(TestCase::fail_alternative_1())]
+    #[test_case(TestCase::fail_repetition_1())]
+    #[test_case(TestCase::fail_repetition_3())]
+    #[test_case(TestCase::fail_look_2())]
+    #[test_case(TestCase::fail_capture_1())]
+    #[test_case(TestCase::fail_words_2())]
+    fn test_solve_adaptive_band(test: TestCase) {
+        let options = Options { band: Some(Band { width: 0, adaptive: true }), ..Options::default() };
+        let actual = solve(&test.pattern.desugar(), &test.text, &options).unwrap();
+        assert_eq!(test.score, actual.score);
+        assert_eq!(test.trace, actual.trace);
+    }
+
+    #[test]
+    fn test_solve_fixed_band() {
+        let test = TestCase::fail_lit_3();
+        let band = |width| Options { band: Some(Band { width, adaptive: false }), ..Options::default() };
+
+        // we cannot skip anything without leaving the diagonal
+        let actual = solve(&test.pattern.desugar(), &test.text, &band(0));
+        assert!(matches!(actual, Err(Error::NoMatchWithinBand(0))));
+
+        let actual = solve(&test.pattern.desugar(), &test.text, &band(1)).unwrap();
+        assert_eq!(test.score, actual.score);
+    }
+
+    #[test]
+    fn test_solve_band_max_nodes() {
+        let test = TestCase::fail_lit_3();
+        // each row stores the pattern indices within one of the text index, except the first and last
+        let nodes = 2 + 4 * 3 + 2;
+        let band = Some(Band { width: 1, adaptive: false });
+
+        let options = Options { band, limits: Limits { max_nodes: Some(nodes), ..Limits::default() }, ..Options::default() };
+        let actual = solve(&test.pattern.desugar(), &test.text, &options).unwrap();
+        assert_eq!(test.score, actual.score);
+
+        let options = Options { band, limits: Limits { max_nodes: Some(nodes - 1), ..Limits::default() }, ..Options::default() };
+        let actual = solve(&test.pattern.desugar(), &test.text, &options);
+        assert!(matches!(actual, Err(Error::ExceededMaxNodes(m)) if m == nodes - 1));
+    }
+
+    #[test]
+    fn test_solve_window_look() {
+        let pattern = pattern(vec![look(r"\b"), lit('a'), lit('b'), look(r"\b")]).desugar();
+        let actual = solve(&pattern, &text("xab").window(1..3), &Options::default()).unwrap();
+        assert_eq!(1, actual.score);
+        assert_eq!(
+            vec![Step::FailLook(patt_look(r"\b")), Step::Hit(Match::Lit('a'), 'a'), Step::Hit(Match::Lit('b'), 'b')],
+            actual.trace
+        );
+    }
+
+    #[test]
+    fn test_solve_protected_default() {
+        let test = TestCase::fail_protected_1();
+        let actual = solve(&test.pattern.desugar(), &test.text, &Options::default()).unwrap();
+        assert_eq!(test.score, actual.score);
+        assert_eq!(
+            vec![
+                Step::Hit(Match::Lit('a'), 'a'),
+                Step::SkipPattern(Match::Lit('"')),
+                Step::Hit(Match::Lit('b'), 'b'),
+                Step::SkipText('"'),
+            ],
+            actual.trace
+        );
+    }
+
+    #[test]
+    fn test_solve_protected_impossible() {
+        let pattern = pattern(lits("a")).desugar();
+        let options = Options { costs: protected(&['"'], None), ..Options::default() };
+        let actual = solve(&pattern, &text("\""), &options);
+        assert!(matches!(actual, Err(Error::NoPossibleMatch)));
+    }
+
+    #[test_case("aa", "a", TieBreak::default(), "a[-a-]")]
+    #[test_case("aa", "a", TieBreak { hits_first: false, ..TieBreak::default() }, "[-a-]a")]
+    #[test_case("ab", "ba", TieBreak::default(), "[-a-]b{+a+}")]
+    #[test_case("ab", "ba", TieBreak { text_first: true, ..TieBreak::default() }, "{+b+}a[-b-]")]
+    #[test_case("abcb", "b", TieBreak::default(), "[-a-]b[-cb-]")]
+    #[test_case("abcb", "b", TieBreak { group_skips: true, ..TieBreak::default() }, "[-abc-]b")]
+    #[test_case("bcba", "b", TieBreak { group_skips: true, hits_first: false, ..TieBreak::default() }, "b[-cba-]")]
+    #[test_case("ab|ac", "a", TieBreak::default(), "a[-b-]")]
+    #[test_case("ab|ac", "a", TieBreak { left_first: false, ..TieBreak::default() }, "a[-c-]")]
+    fn test_solve_tie_break(pattern_str: &str, text_str: &str, tie_break: TieBreak, diff: &str) {
+        let pattern = parse_pattern(pattern_str, false).unwrap().desugar();
+        for solver in [Solver::Table, Solver::LinearSpace] {
+            let options = Options { tie_break, solver, ..Options::default() };
+            let actual = solve(&pattern, &text(text_str), &options).unwrap();
+            assert_eq!(diff, DiffOutput::new(&actual.score, &actual.trace).to_string());
+        }
+    }
+
+    #[test_case("hi yo", "hi you", "hi [-yo-]{+you+}")]
+    #[test_case("a b", "ab b", "[-a-]{+ab+} b")]
+    #[test_case("hello world", "helloworld", "[-hello world-]{+helloworld+}")]
+    #[test_case("hi (?i:y)", "hi Yo", "hi [-y-]{+Yo+}")]
+    fn test_solve_whole_words(pattern_str: &str, text_str: &str, diff: &str) {
+        let pattern = parse_pattern(pattern_str, false).unwrap().desugar();
+        let options = Options { tokens: Tokens::Words, ..Options::default() };
+        let actual = solve(&pattern, &words(text_str), &options).unwrap();
+        assert_eq!(diff, DiffOutput::new(&actual.score, &actual.trace).to_string());
+    }
+
+    #[test_case(r#""(.*?)""#, r#"say "hi" and "bye" now"#, "hi")]
+    #[test_case(r#""(.*)""#, r#"say "hi" and "bye" now"#, r#"hi" and "bye"#)]
+    #[test_case("a(b*?)b*", "abb", "")]
+    #[test_case("a(b*)b*?", "abb", "bb")]
+    #[test_case("a(b+?)b*", "abb", "b")]
+    #[test_case("a(b{0,2}?)b*", "abb", "")]
+    #[test_case("a(b{0,2})b*", "abb", "bb")]
+    #[test_case("a(b?)b?", "ab", "b")]
+    #[test_case("a(b??)b?", "ab", "")]
+    fn test_solve_lazy_repetition(pattern_str: &str, text_str: &str, captured: &str) {
+        let pattern = parse_pattern(pattern_str, false).unwrap().desugar();
+        let options = Options { search: true, ..Options::default() };
+        let actual = solve(&pattern, &text(text_str), &options).unwrap();
+        assert_eq!(0, actual.score);
+        let captures = Captures::new_at(&actual.trace, &pattern.groups(), actual.range.start);
+        assert_eq!(Some(captured), captures.groups[1].as_ref().map(|capture| capture.text.as_str()));
+    }
+
+    #[test]
+    fn test_solve_group_skips_max_cost() {
+        let pattern = parse_pattern("abcb", false).unwrap().desugar();
+        let tie_break = TieBreak { group_skips: true, ..TieBreak::default() };
+        let within = Options { tie_break, max_cost: Some(3), ..Options::default() };
+        assert_eq!(3, solve(&pattern, &text("b"), &within).unwrap().score);
+
+        let below = Options { tie_break, max_cost: Some(2), ..Options::default() };
+        assert!(matches!(solve(&pattern, &text("b"), &below), Err(Error::NoMatchWithinMaxCost(2))));
+    }
+
+    fn protected(chars: &[char], skip_protected: Option<usize>) -> Costs {
+        Costs { protected: chars.to_vec(), skip_protected, ..Costs::default() }
+    }
+
+    proptest! {
+        #[test]
+        fn max_cost_keeps_score(pattern_str in r"(\(a\|bc\|\)\*|[ab]|b\?|\(ab\)\+){0,5}", text_str in "[abc]{0,8}") {
+            let pattern = parse_pattern(&pattern_str, false).unwrap().desugar();
+            let text = text(&text_str);
+            let score = solve(&pattern, &text, &Options::default()).unwrap().score;
+
+            let within = Options { max_cost: Some(score), ..Options::default() };
+            prop_assert_eq!(score, solve(&pattern, &text, &within).unwrap().score);
+
+            if score > 0 {
+                let below = Options { max_cost: Some(score - 1), ..Options::default() };
+                prop_assert!(matches!(solve(&pattern, &text, &below), Err(Error::NoMatchWithinMaxCost(_))));
+            }
+        }
+
+        #[test]
+        fn adaptive_band_keeps_solution(
+            pattern_str in r"(\(a\|bc\|\)\*|[ab ]|x[ab]\*|\\b|\(\?i:b\)|\(x\)\+|\(xy\)\?){0,6}",
+            text_str in "[abcxy ]{0,12}",
+            (by_words, ignore_whitespace, skip_text, gap_open, replace) in
+                (any::<bool>(), any::<bool>(), 0..3usize, 0..3usize, prop::option::of(1..3usize)),
+            width in 0..3usize,
+        ) {
+            let pattern = parse_pattern(&pattern_str, false).unwrap().desugar();
+            let text = if by_words { words(&text_str) } else { text(&text_str) };
+            let options = Options {
+                costs: Costs { skip_text, gap_open, replace, transpose: replace, ..Costs::default() },
+                tokens: if by_words { Tokens::Words } else { Tokens::Chars },
+                ignore_whitespace,
+                ..Options::default()
+            };
+            let expected = solve(&pattern, &text, &options).unwrap();
+            let banded = Options { band: Some(Band { width, adaptive: true }), ..options };
+            prop_assert_eq!(expected, solve(&pattern, &text, &banded).unwrap());
+        }
+
+        #[test]
+        fn search_same_as_best_substring(
+            pattern_str in r"(\(a\|bc\|\)\*|[ab]|b\?|\(ab\)\+|\(cb\)\{2\}|a\{1,2\}\??|\\b){0,4}",
+            text_str in "[abc ]{0,8}",
+        ) {
+            let pattern = parse_pattern(&pattern_str, false).unwrap().desugar();
+            let text = text(&text_str);
+            let len = text.atoms.len();
+            let options = Options { search: true, ..Options::default() };
+            let actual = solve(&pattern, &text, &options).unwrap();
+            // the oracle matches the whole of every substring, and picks the best one
+            let score_within = |range: Range<usize>| solve(&pattern, &text.window(range), &Options::default()).unwrap().score;
+            let best = (0..=len).flat_map(|start| (start..=len).map(move |end| start..end)).map(score_within).min();
+            prop_assert_eq!(best, Some(actual.score));
+            prop_assert_eq!(actual.score, score_within(actual.range));
+        }
+
+        #[test]
+        fn tie_break_keeps_score(
+            pattern_str in r"(\(a\|bc\|\)\*|[ab]|b\?|\(ab\)\+){0,5}",
+            text_str in "[abc]{0,8}",
+            (hits_first, text_first, group_skips, left_first) in (any::<bool>(), any::<bool>(), any::<bool>(), any::<bool>()),
+        ) {
+            let pattern = parse_pattern(&pattern_str, false).unwrap().desugar();
+            let text = text(&text_str);
+            let expected = solve(&pattern, &text, &Options::default()).unwrap();
+            let tie_break = TieBreak { hits_first, text_first, group_skips, left_first };
+            let actual = solve(&pattern, &text, &Options { tie_break, ..Options::default() }).unwrap();
+            prop_assert_eq!(expected.score, actual.score);
+        }
+
+        #[test]
+        fn counted_repetition_same_as_expanded(
+            pattern_str in r"(\(a\|bc\|\)\{0,2\}\??|[ab]\{1,3\}\??|b\{2\}\??|\(ab\?\)\{1,\}\??|\(x\{2\}y\)\*|\(a\{1,2\}\??c\)\{1,2\}|x){0,4}",
+            text_str in "[abcxy]{0,10}",
+            replace in prop::option::of(1..3usize),
+        ) {
+            let sugar = parse_pattern(&pattern_str, false).unwrap();
+            let pattern = sugar.desugar();
+            let text = text(&text_str);
+            // transpositions never cross the end of a repetition, so we can only compare without them
+            let options = Options { costs: Costs { replace, ..Costs::default() }, ..Options::default() };
+            let expected = solve(&sugar.desugar_expanded(), &text, &options).unwrap();
+            let actual = solve(&pattern, &text, &options).unwrap();
+            // the expanded form may repeat an iteration which matched nothing where the solver ends
+            // the repetition, but that never changes the score
+            prop_assert_eq!(expected.score, actual.score);
+            // matching the pattern once inside another counted repetition counts every iteration
+            // differently, but must find exactly the same match
+            let once = Bounds { minimum: 1, maximum: Some(1), greedy: true };
+            let nested = Pattern { elems: vec![ElementCore::Repetition(once, pattern)] };
+            prop_assert_eq!(solve(&nested, &text, &options).unwrap(), actual);
+        }
+    }
 }
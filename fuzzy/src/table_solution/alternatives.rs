@@ -0,0 +1,385 @@
+//! Finds other good matches, as well as the optimal one.
+//!
+//! There are often several equally good ways to match a text, e.g. skipping a pattern character
+//! before or after skipping a text character, and the [table solver](super) only reports the first
+//! one it finds. Once the table holds the optimal score of every node we can reach, we know the
+//! best score of any path that takes a given step, so we can explore paths through the table best
+//! first. We only ever extend a path which can still finish at its score, so we find the paths in
+//! order of increasing score without exploring hopeless ones.
+//!
+//! Many paths have the same trace, e.g. when both sides of an alternative match nothing, or when
+//! they skip the same pattern and text characters in a different order. We only report one of
+//! them, and we only explore one of the paths which reach a node with the same score and trace so
+//! far, as they all finish the same way.
+
+use super::{Config, FORBIDDEN, Ix, State, StepType, calculate_optimal_path, check_score, flatten, solution};
+use crate::{Atoms, ElementCore, Match, Options, Pattern, Solution, Solver, Step};
+use crate::error::Error;
+use crate::flat_pattern::FlatPattern;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::sync::Arc;
+
+/// Finds every match which is as good as the optimal one, up to `limit` of them.
+///
+/// The first solution is the one [`solve`](super::solve) finds. We only report distinct solutions,
+/// where solutions which only differ in the order of adjacent pattern and text skips are the same.
+/// This needs the table solver, without a band, so we return [`Error::SeveralMatchesNeedTable`]
+/// when [`Options::solver`] or [`Options::band`] asks for anything else.
+pub fn solve_all(pattern: &Pattern<ElementCore>, text: &Atoms, options: &Options, limit: usize) -> Result<Vec<Solution>, Error> {
+    let flat = flatten(pattern, text.tokens, options.ignore_whitespace);
+    solve_all_flat(&Arc::new(flat), text, options, limit)
+}
+
+/// Like [`solve_all`], for a pattern we already flattened, per [`flatten`].
+pub fn solve_all_flat(pattern: &Arc<FlatPattern>, text: &Atoms, options: &Options, limit: usize) -> Result<Vec<Solution>, Error> {
+    let conf = Config::from_flat(pattern.clone(), text, options);
+    let state = solve_every_node(&conf, options)?;
+    let score = state.get(conf.start()).score();
+    best_paths(&conf, &state, options, limit, score)
+}
+
+/// Finds the `k` best distinct matches, in order of increasing score.
+///
+/// Matches with the same score are in the same order as [`solve_all`] finds them, and we leave
+/// out any match which costs more than [`Options::max_cost`].
+pub fn solve_k_best(pattern: &Pattern<ElementCore>, text: &Atoms, options: &Options, k: usize) -> Result<Vec<Solution>, Error> {
+    let flat = flatten(pattern, text.tokens, options.ignore_whitespace);
+    solve_k_best_flat(&Arc::new(flat), text, options, k)
+}
+
+/// Like [`solve_k_best`], for a pattern we already flattened, per [`flatten`].
+pub fn solve_k_best_flat(pattern: &Arc<FlatPattern>, text: &Atoms, options: &Options, k: usize) -> Result<Vec<Solution>, Error> {
+    let conf = Config::from_flat(pattern.clone(), text, options);
+    let state = solve_every_node(&conf, options)?;
+    let max_score = options.max_cost.map_or(FORBIDDEN - 1, |max_cost| conf.budget(max_cost));
+    best_paths(&conf, &state, options, k, max_score)
+}
+
+/// Calculates the optimal score of every node we can reach from the start node.
+///
+/// We cannot prune nodes which cost more than [`Options::max_cost`], as we need the exact score
+/// of every node to know which paths to explore.
+fn solve_every_node(conf: &Config, options: &Options) -> Result<State, Error> {
+    if options.solver != Solver::Table || options.band.is_some() {
+        return Err(Error::SeveralMatchesNeedTable);
+    }
+    let mut state = State::new(conf, options.limits.max_nodes, None)?;
+    calculate_optimal_path(conf, &mut state, usize::MAX, options.limits.max_steps)?;
+    let start_node = state.get(conf.start());
+    if !start_node.is_finished() {
+        return Err(Error::IncompleteFinalState);
+    }
+    check_score(conf.score(start_node.score()), options)?;
+    Ok(state)
+}
+
+/// A path we are exploring, which ends at `ix`.
+#[derive(Eq, PartialEq, Ord, PartialOrd)]
+struct Candidate {
+    /// The lowest score of any path which starts with this one.
+    score: Reverse<usize>,
+    /// The order we found the candidates in. We explore the latest candidate first on ties, so we
+    /// follow the first optimal step from each node, like the table solver.
+    order: usize,
+    /// The index of the last step of the path in our list of steps, if it has any.
+    last: Option<usize>,
+    trace: TraceId,
+}
+
+/// Identifies the trace of a path, up to the order of the skips in each run of adjacent skips.
+///
+/// Paths which reach the same node with the same id have the same trace so far, so they can only
+/// finish with the same solutions. The ids only tell paths apart by the position of each step, so
+/// some paths with different ids still have the same trace.
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+struct TraceId {
+    /// The id of the trace before the current run of skips.
+    before: usize,
+    /// The id of the pattern elements we skipped in the current run, or 0 if there are none.
+    skips: usize,
+    /// The text index where the current run started. We skipped the text from here to the node.
+    skips_from: usize,
+}
+
+/// Part of a trace, which we number by the id of the trace before it, per [`TraceIds`].
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+enum Event {
+    /// Skips the pattern element starting at this index, per [`Config::blocks`].
+    SkipPattern(usize),
+    /// A run of skips, with the id of the pattern elements it skipped, and the text it skipped.
+    Skips(usize, usize, usize),
+    /// Any other step with a trace, from the pattern element and text at these indices.
+    Step(usize, usize, StepType),
+    /// Skips the text at this index for free, outside the match.
+    Free(usize),
+}
+
+/// Numbers each trace we see, per [`TraceId`].
+struct TraceIds {
+    /// The first index of the flat pattern element at each index, per [`Config::blocks`].
+    starts: Vec<usize>,
+    ids: HashMap<(usize, Event), usize>,
+}
+
+impl TraceIds {
+    fn new(conf: &Config) -> Self {
+        let starts = conf.blocks().into_iter().map(|(start, _)| start).collect();
+        TraceIds { starts, ids: HashMap::new() }
+    }
+
+    fn id(&mut self, before: usize, event: Event) -> usize {
+        let next = self.ids.len() + 1;
+        *self.ids.entry((before, event)).or_insert(next)
+    }
+
+    /// Returns the id of the trace before `from`, including the current run of skips.
+    fn finish_run(&mut self, trace: TraceId, from: Ix) -> usize {
+        if trace.skips == 0 && trace.skips_from == from.text {
+            trace.before
+        } else {
+            self.id(trace.before, Event::Skips(trace.skips, trace.skips_from, from.text))
+        }
+    }
+
+    /// Returns the id of a path's trace after it takes this step.
+    fn step(&mut self, conf: &Config, trace: TraceId, from: Ix, step_type: StepType, next: Ix) -> TraceId {
+        let pattern = self.starts[from.pattern];
+        if conf.is_free(from, step_type) {
+            let before = self.finish_run(trace, from);
+            let before = self.id(before, Event::Free(from.text));
+            return TraceId { before, skips: 0, skips_from: next.text };
+        }
+        match step_type {
+            // the text we skip is between the start of the run and the next node
+            StepType::SkipText => trace,
+            StepType::SkipPattern => TraceId { skips: self.id(trace.skips, Event::SkipPattern(pattern)), ..trace },
+            _ if !step_type.has_trace() => trace,
+            _ => {
+                let before = self.finish_run(trace, from);
+                let before = self.id(before, Event::Step(pattern, from.text, step_type));
+                TraceId { before, skips: 0, skips_from: next.text }
+            }
+        }
+    }
+}
+
+/// Finds up to `count` distinct solutions which score no more than `max_score`, best first.
+fn best_paths(conf: &Config, state: &State, options: &Options, count: usize, max_score: usize) -> Result<Vec<Solution>, Error> {
+    let start_ix = conf.start();
+    let end_ix = conf.end();
+    // every step of every path we explore, with the index of the step before it
+    let mut steps: Vec<(Option<usize>, Ix, StepType, Ix)> = vec![];
+    let mut candidates = BinaryHeap::new();
+    let mut trace_ids = TraceIds::new(conf);
+    // the node, score and trace id of every path we explored
+    let mut explored = HashSet::new();
+    let mut solutions = vec![];
+    // the score, range and normalised trace of each solution
+    let mut distinct = vec![];
+    let mut loop_counter = 0;
+
+    let start_score = state.get(start_ix).score();
+    if start_score <= max_score {
+        let trace = TraceId::default();
+        candidates.push(Candidate { score: Reverse(start_score), order: 0, last: None, trace });
+    }
+
+    while let Some(Candidate { score: Reverse(score), last, trace, .. }) = candidates.pop() {
+        if solutions.len() >= count {
+            break;
+        }
+        loop_counter += 1;
+        if loop_counter > options.limits.max_steps {
+            return Err(Error::ExceededMaxSteps(options.limits.max_steps));
+        }
+
+        let ix = last.map_or(start_ix, |last| steps[last].3);
+        // an earlier path reached this node with the same score and trace, so it finishes the same way
+        if !explored.insert((ix, score, trace)) {
+            continue;
+        }
+        if ix == end_ix {
+            let path = path(&steps, last);
+            let found = solution(conf, conf.score(score), &path)?;
+            let key = (found.score, found.range.clone(), normalise(&found.trace));
+            if !distinct.contains(&key) {
+                distinct.push(key);
+                solutions.push(found);
+            }
+            continue;
+        }
+
+        // the score so far, which is the same for every step from here
+        let before = score - state.get(ix).score();
+        let step_types = conf.node_step_types(&ix);
+        // we push the steps in reverse, so we explore the first optimal step first on ties
+        for step_type in step_types.iter().collect::<Vec<_>>().into_iter().rev() {
+            let cost = conf.cost(ix, step_type);
+            let next = conf.step(ix, step_type);
+            let next_score = state.get(next).score();
+            if cost == FORBIDDEN || next_score == FORBIDDEN {
+                continue;
+            }
+            let score = before.saturating_add(cost).saturating_add(next_score);
+            if score <= max_score {
+                let trace = trace_ids.step(conf, trace, ix, step_type, next);
+                steps.push((last, ix, step_type, next));
+                candidates.push(Candidate { score: Reverse(score), order: steps.len(), last: Some(steps.len() - 1), trace });
+            }
+        }
+    }
+
+    Ok(solutions)
+}
+
+/// Puts the pattern skips in each run of adjacent skips before the text skips, so traces which only
+/// differ in the order of those skips are equal.
+fn normalise(trace: &[Step<Match, char>]) -> Vec<Step<Match, char>> {
+    let is_skip = |step: &Step<Match, char>| matches!(step, Step::SkipPattern(_) | Step::SkipText(_));
+    let mut result = Vec::with_capacity(trace.len());
+    for run in trace.chunk_by(|a, b| is_skip(a) == is_skip(b)) {
+        if is_skip(&run[0]) {
+            result.extend(run.iter().filter(|step| matches!(step, Step::SkipPattern(_))).cloned());
+            result.extend(run.iter().filter(|step| matches!(step, Step::SkipText(_))).cloned());
+        } else {
+            result.extend(run.iter().cloned());
+        }
+    }
+    result
+}
+
+/// Returns the steps of the path which ends with this step, in order.
+fn path(steps: &[(Option<usize>, Ix, StepType, Ix)], last: Option<usize>) -> Vec<(Ix, StepType, Ix)> {
+    let mut path = vec![];
+    let mut current = last;
+    while let Some(index) = current {
+        let (before, from, step_type, next) = steps[index];
+        path.push((from, step_type, next));
+        current = before;
+    }
+    path.reverse();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{solve_all, solve_k_best};
+    use crate::{Band, Limits, Match, Options, Solver, Step};
+    use crate::diff_output::DiffOutput;
+    use crate::error::Error;
+    use crate::regex_pattern::parse_pattern;
+    use crate::table_solution::solve;
+    use crate::test_cases::{TestCase, text};
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    #[test]
+    fn test_solve_all_orders() {
+        let pattern = parse_pattern("ab", false).unwrap().desugar();
+        let actual = solve_all(&pattern, &text("ba"), &Options::default(), 10).unwrap();
+        // we can hit either character, and skip the other one on both sides
+        assert_eq!(2, actual.len());
+        assert!(actual.iter().all(|solution| solution.score == 2));
+        assert_eq!(
+            vec![
+                Step::SkipText('b'),
+                Step::Hit(Match::Lit('a'), 'a'),
+                Step::SkipPattern(Match::Lit('b')),
+            ],
+            actual[1].trace,
+        );
+    }
+
+    // skipping the same pattern and text characters in a different order is the same match
+    #[test_case(TestCase::fail_class_1(), 1)]
+    #[test_case(TestCase::fail_lit_3(), 1)]
+    #[test_case(TestCase::match_repetition_5(), 1)]
+    #[test_case(TestCase::fail_alternative_1(), 1)]
+    fn test_solve_all(test: TestCase, count: usize) {
+        let actual = solve_all(&test.pattern.desugar(), &test.text, &Options::default(), 10).unwrap();
+        assert_eq!(count, actual.len());
+        assert_eq!(test.trace, actual[0].trace);
+    }
+
+    #[test]
+    fn test_solve_all_limit() {
+        let pattern = parse_pattern("ab", false).unwrap().desugar();
+        let actual = solve_all(&pattern, &text("ba"), &Options::default(), 1).unwrap();
+        assert_eq!(1, actual.len());
+    }
+
+    #[test]
+    fn test_solve_all_needs_table() {
+        let pattern = parse_pattern("ab", false).unwrap().desugar();
+        let linear_space = Options { solver: Solver::LinearSpace, ..Options::default() };
+        let band = Options { band: Some(Band { width: 3, adaptive: true }), ..Options::default() };
+        for options in [linear_space, band] {
+            assert!(matches!(solve_all(&pattern, &text("ba"), &options, 1), Err(Error::SeveralMatchesNeedTable)));
+            assert!(matches!(solve_k_best(&pattern, &text("ba"), &options, 1), Err(Error::SeveralMatchesNeedTable)));
+        }
+    }
+
+    #[test]
+    fn test_solve_k_best_distinct() {
+        let pattern = parse_pattern("abc", false).unwrap().desugar();
+        let actual = solve_k_best(&pattern, &text("xbz"), &Options::default(), 3).unwrap();
+        let diffs: Vec<String> = actual.iter()
+            .map(|solution| DiffOutput::new(&solution.score, &solution.trace).to_string())
+            .collect();
+        // every match which does not hit the b skips the same characters
+        assert_eq!(vec!["[-a-]{+x+}b[-c-]{+z+}", "[-abc-]{+xbz+}"], diffs);
+    }
+
+    #[test]
+    fn test_solve_all_empty_alternatives() {
+        // every path through the alternatives has the same trace, so we only explore one of them
+        let pattern = parse_pattern(&format!("{}a", "(?:|)".repeat(100)), false).unwrap().desugar();
+        let options = Options { limits: Limits { max_steps: 10_000, ..Limits::default() }, ..Options::default() };
+        let actual = solve_all(&pattern, &text("b"), &options, 3).unwrap();
+        assert_eq!(1, actual.len());
+    }
+
+    #[test]
+    fn test_solve_k_best() {
+        let pattern = parse_pattern("ab|b", false).unwrap().desugar();
+        let actual = solve_k_best(&pattern, &text("b"), &Options::default(), 3).unwrap();
+        let scores: Vec<usize> = actual.iter().map(|solution| solution.score).collect();
+        assert_eq!(vec![0, 1, 2], scores);
+        assert_eq!(vec![Step::Hit(Match::Lit('b'), 'b')], actual[0].trace);
+        assert_eq!(vec![Step::SkipPattern(Match::Lit('a')), Step::Hit(Match::Lit('b'), 'b')], actual[1].trace);
+    }
+
+    #[test]
+    fn test_solve_k_best_max_cost() {
+        let pattern = parse_pattern("ab|b", false).unwrap().desugar();
+        let options = Options { max_cost: Some(1), ..Options::default() };
+        let actual = solve_k_best(&pattern, &text("b"), &options, 10).unwrap();
+        assert_eq!(2, actual.len());
+
+        let actual = solve_k_best(&pattern, &text("c"), &options, 10);
+        assert!(matches!(actual, Err(Error::NoMatchWithinMaxCost(1))));
+    }
+
+    proptest! {
+        #[test]
+        fn k_best_starts_with_optimal(
+            pattern_str in r"(\(a\|bc\|\)\*|[ab]|b\?|\(ab\)\+){0,4}",
+            text_str in "[abc]{0,6}",
+            k in 1..6usize,
+        ) {
+            let pattern = parse_pattern(&pattern_str, false).unwrap().desugar();
+            let text = text(&text_str);
+            let expected = solve(&pattern, &text, &Options::default()).unwrap();
+            let all = solve_all(&pattern, &text, &Options::default(), k).unwrap();
+            let best = solve_k_best(&pattern, &text, &Options::default(), k).unwrap();
+            prop_assert_eq!(&expected, &all[0]);
+            prop_assert_eq!(&expected, &best[0]);
+            prop_assert!(best.windows(2).all(|pair| pair[0].score <= pair[1].score));
+            prop_assert!(all.iter().all(|solution| solution.score == expected.score));
+            // the co-optimal solutions come first, unless there are more than k of them
+            let co_optimal = best.iter().take_while(|solution| solution.score == expected.score).count();
+            prop_assert_eq!(all.len().min(k), co_optimal);
+        }
+    }
+}
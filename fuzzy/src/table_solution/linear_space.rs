@@ -0,0 +1,340 @@
+//! A solver which only keeps a few rows of the table in memory at once.
+//!
+//! The [table solver](super) stores a node for every text and pattern index, so the memory it needs
+//! grows with the product of their lengths. This solver calculates the same scores one row at a
+//! time, where each row holds the nodes for a single text index. Steps never move backwards
+//! through the text, so we can calculate the rows from the end of the text to the start, and only
+//! keep the few rows that the next row can step to.
+//!
+//! Following the optimal path from the start needs every row though. Instead of storing them, we
+//! divide and conquer: we calculate the rows from the end of a text range down to its midpoint, and
+//! keep the rows we need to restart from the midpoint. Then we follow the optimal path through the
+//! first half, recalculating its rows from the midpoint, before we do the same for the second
+//! half. We only store a logarithmic number of these restart points at once, at the cost of
+//! calculating each row a logarithmic number of times.
+//!
+//! Each node picks the first optimal step in the same order as the table solver, so both solvers
+//! find exactly the same solution.
+
+use super::{Config, Ix, StepType, FORBIDDEN, check_score, solution};
+use crate::{Options, Solution};
+use crate::error::Error;
+use std::collections::BTreeMap;
+
+/// The number of rows we store at once when we follow the optimal path through a text range.
+const BASE_ROWS: usize = 64;
+
+/// The scores of each node in a row, for every row we currently store.
+type Rows = BTreeMap<usize, Vec<usize>>;
+
+/// Finds the optimal match between the pattern and text.
+pub fn solve(conf: &Config, options: &Options) -> Result<Solution, Error> {
+    solve_with(conf, options, BASE_ROWS)
+}
+
+fn solve_with(conf: &Config, options: &Options, base_rows: usize) -> Result<Solution, Error> {
+    let mut solver = Solver::new(conf, options, base_rows)?;
+    let mut current = conf.start();
+    let mut path = vec![];
+    solver.walk(0, conf.text.len() + 1, &Rows::new(), &mut current, &mut path)?;
+    if current != conf.end() {
+        return Err(Error::IncompleteFinalState);
+    }
+    let score = solver.score.ok_or(Error::IncompleteFinalState)?;
+    check_score(score, options)?;
+    solution(conf, score, &path)
+}
+
+struct Solver<'a> {
+    conf: &'a Config,
+    /// The index of the first copy of each flat pattern element, and how many copies there are.
+    ///
+    /// See [`Ix::rep_off`] for why we copy elements inside repetitions. We use this to find the
+    /// index for each node in a row.
+    blocks: Vec<(usize, usize)>,
+    /// The index in [`Self::blocks`] of each cell in a row, per [`Config::cell`].
+    cell_blocks: Vec<usize>,
+    gap_len: usize,
+    /// The highest text index that any row up to this one can step to.
+    reaches: Vec<usize>,
+    base_rows: usize,
+    max_steps: usize,
+    steps: usize,
+    /// The score of the start node, once we calculate it.
+    score: Option<usize>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Mark {
+    Ready,
+    Working,
+    Done,
+}
+
+impl<'a> Solver<'a> {
+    fn new(conf: &'a Config, options: &Options, base_rows: usize) -> Result<Self, Error> {
+        let blocks = conf.blocks();
+        let cell_blocks = conf.cell_starts.windows(2)
+            .enumerate()
+            .flat_map(|(block, cells)| std::iter::repeat_n(block, cells[1] - cells[0]))
+            .collect();
+        let gap_len = if conf.has_gaps() { 2 } else { 1 };
+        let reaches = Self::reaches(conf);
+        let solver = Solver {
+            conf,
+            blocks,
+            cell_blocks,
+            gap_len,
+            reaches,
+            base_rows: base_rows.max(1),
+            max_steps: options.limits.max_steps,
+            steps: 0,
+            score: None,
+        };
+        if let Some(max_nodes) = options.limits.max_nodes {
+            if solver.max_rows().checked_mul(solver.row_len()).is_none_or(|nodes| nodes > max_nodes) {
+                return Err(Error::ExceededMaxNodes(max_nodes));
+            }
+        }
+        Ok(solver)
+    }
+
+    fn reaches(conf: &Config) -> Vec<usize> {
+        let len = conf.text.len();
+        let mut reaches = Vec::with_capacity(len + 1);
+        let mut reach = 0;
+        for text in 0..=len {
+            // transpositions move two characters, while whitespace hits can move past a whole run
+            let next = if text < len {
+                conf.token_end(text).max(conf.whitespace_end(text)).max(text + 2).min(len)
+            } else {
+                len
+            };
+            reach = reach.max(next);
+            reaches.push(reach);
+        }
+        reaches
+    }
+
+    /// Returns the most rows we store at once: a restart point for each level of recursion, and
+    /// the rows of a single text range.
+    fn max_rows(&self) -> usize {
+        let len = self.conf.text.len() + 1;
+        let window = (0..len).map(|text| self.reaches[text] + 1 - text).max().unwrap_or(1);
+        let depth = (usize::BITS - len.leading_zeros()) as usize + 1;
+        depth * window + self.base_rows + window
+    }
+
+    fn row_len(&self) -> usize {
+        self.cell_blocks.len() * self.gap_len
+    }
+
+    fn slot(&self, ix: &Ix) -> usize {
+        self.conf.cell(ix) * self.gap_len + ix.gap as usize
+    }
+
+    fn ix(&self, text: usize, slot: usize) -> Ix {
+        let cell = slot / self.gap_len;
+        let gap = slot % self.gap_len == 1;
+        let block = self.cell_blocks[cell];
+        // the last cell is the start of the pattern before the match starts, per [`Ix::leading`]
+        let Some((pattern, reps)) = self.blocks.get(block).copied() else {
+            return Ix { text, pattern: 0, reps: 1, rep_off: 0, count: 0, gap, leading: true };
+        };
+        let rep_off = block - pattern;
+        let count = cell - self.conf.cell_starts[block];
+        Ix { text, pattern, reps, rep_off, count, gap, leading: false }
+    }
+
+    /// Follows the optimal path through the rows from `start` up to, but not including, `end`.
+    ///
+    /// The `end_rows` are the rows we need to calculate the row before `end`.
+    fn walk(&mut self, start: usize, end: usize, end_rows: &Rows, current: &mut Ix, path: &mut Vec<(Ix, StepType, Ix)>) -> Result<(), Error> {
+        if current.text >= end {
+            return Ok(());
+        }
+        if end - start <= self.base_rows {
+            let rows = self.calculate(end_rows.clone(), start, end, true)?;
+            if start == 0 {
+                self.score = Some(self.conf.score(rows[&0][self.slot(&self.conf.start())]));
+            }
+            while current.text < end && *current != self.conf.end() {
+                let steps = self.steps(current);
+                let (_, step_type, next) = self.optimal(*current, &steps, |next| rows[&next.text][self.slot(&next)])
+                    .ok_or_else(|| Error::NoNodeType(format!("{:?}", current)))?;
+                path.push((*current, step_type, next));
+                *current = next;
+            }
+        } else {
+            let mid = start + (end - start) / 2;
+            let mid_rows = self.calculate(end_rows.clone(), mid, end, false)?;
+            self.walk(start, mid, &mid_rows, current, path)?;
+            self.walk(mid, end, end_rows, current, path)?;
+        }
+        Ok(())
+    }
+
+    /// Calculates the rows from `end - 1` down to `start`.
+    ///
+    /// Unless we `keep` every row, we drop the rows that rows before `start` can no longer reach.
+    fn calculate(&mut self, mut rows: Rows, start: usize, end: usize, keep: bool) -> Result<Rows, Error> {
+        for text in (start..end).rev() {
+            let row = self.row(text, &rows)?;
+            rows.insert(text, row);
+            if !keep && text > 0 {
+                let _ = rows.split_off(&(self.reaches[text - 1] + 1));
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Calculates the score of every node in this row, given the rows after it.
+    ///
+    /// Steps within the row can move backwards through the pattern, e.g. when we restart a
+    /// repetition, so we calculate each node after the nodes it steps to, like the table solver.
+    fn row(&mut self, text: usize, rows: &Rows) -> Result<Vec<usize>, Error> {
+        let len = self.row_len();
+        let mut scores = vec![FORBIDDEN; len];
+        let mut marks = vec![Mark::Ready; len];
+        for root in (0..len).rev() {
+            let mut stack = vec![root];
+            while let Some(&slot) = stack.last() {
+                if marks[slot] == Mark::Done {
+                    stack.pop();
+                    continue;
+                }
+                let ix = self.ix(text, slot);
+                let steps = self.steps(&ix);
+                let pending = steps.iter()
+                    .map(|(_, next)| *next)
+                    .find(|next| next.text == text && marks[self.slot(next)] != Mark::Done);
+                match pending {
+                    Some(next) if marks[self.slot(&next)] == Mark::Working => {
+                        return Err(Error::CyclicNode(format!("{:?}", next)));
+                    }
+                    Some(next) => {
+                        marks[slot] = Mark::Working;
+                        stack.push(self.slot(&next));
+                    }
+                    None => {
+                        self.steps += 1;
+                        if self.steps > self.max_steps {
+                            return Err(Error::ExceededMaxSteps(self.max_steps));
+                        }
+                        let score = if ix == self.conf.end() {
+                            0
+                        } else {
+                            let score_of = |next: Ix| if next.text == text {
+                                scores[self.slot(&next)]
+                            } else {
+                                rows[&next.text][self.slot(&next)]
+                            };
+                            self.optimal(ix, &steps, score_of).map_or(FORBIDDEN, |(score, _, _)| score)
+                        };
+                        scores[slot] = score;
+                        marks[slot] = Mark::Done;
+                        stack.pop();
+                    }
+                }
+            }
+        }
+        Ok(scores)
+    }
+
+    /// Returns each step we can take from this node, and the node it steps to.
+    ///
+    /// Some nodes in a row can never be reached, e.g. the end node inside a gap, so they do not
+    /// have any steps.
+    fn steps(&self, ix: &Ix) -> Vec<(StepType, Ix)> {
+        self.conf.node_step_types(ix)
+            .iter()
+            .map(|step_type| (step_type, self.conf.step(*ix, step_type)))
+            .collect()
+    }
+
+    /// Picks the first optimal step from this node, given the score of each node it can step to.
+    fn optimal(&self, ix: Ix, steps: &[(StepType, Ix)], score_of: impl Fn(Ix) -> usize) -> Option<(usize, StepType, Ix)> {
+        let mut best: Option<(usize, StepType, Ix)> = None;
+        for (step_type, next) in steps.iter().copied() {
+            let cost = self.conf.cost(ix, step_type);
+            let score = if cost == FORBIDDEN { FORBIDDEN } else { score_of(next).saturating_add(cost) };
+            if best.is_none_or(|(best_score, _, _)| score < best_score) {
+                best = Some((score, step_type, next));
+            }
+        }
+        best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::solve_with;
+    use crate::{Costs, Limits, Options, TieBreak, Tokens};
+    use crate::error::Error;
+    use crate::regex_pattern::parse_pattern;
+    use crate::table_solution::{Config, solve};
+    use crate::test_cases::{TestCase, text, words};
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    #[test_case(TestCase::match_empty())]
+    #[test_case(TestCase::fail_empty_1())]
+    #[test_case(TestCase::fail_lit_3())]
+    #[test_case(TestCase::fail_class_2())]
+    #[test_case(TestCase::match_alternative_3())]
+    #[test_case(TestCase::fail_alternative_1())]
+    #[test_case(TestCase::match_repetition_5())]
+    #[test_case(TestCase::fail_repetition_3())]
+    #[test_case(TestCase::fail_look_2())]
+    #[test_case(TestCase::match_capture_3())]
+    #[test_case(TestCase::fail_capture_1())]
+    #[test_case(TestCase::fail_words_2())]
+    #[test_case(TestCase::fail_lines_2())]
+    #[test_case(TestCase::fail_caseless_1())]
+    fn test_solve(test: TestCase) {
+        for base_rows in [1, 2, 64] {
+            let pattern = test.pattern.desugar();
+            let conf = Config::new(&pattern, &test.text, &Options::default());
+            let actual = solve_with(&conf, &Options::default(), base_rows).unwrap();
+            assert_eq!(test.score, actual.score);
+            assert_eq!(test.trace, actual.trace);
+        }
+    }
+
+    #[test]
+    fn test_solve_max_nodes() {
+        let test = TestCase::fail_lit_3();
+        let options = Options { limits: Limits { max_nodes: Some(10), ..Limits::default() }, ..Options::default() };
+        let conf = Config::new(&test.pattern.desugar(), &test.text, &options);
+        assert!(matches!(solve_with(&conf, &options, 2), Err(Error::ExceededMaxNodes(10))));
+    }
+
+    fn options() -> impl Strategy<Value = Options> {
+        (any::<bool>(), any::<bool>(), any::<bool>(), 0..3usize, prop::option::of(1..3usize), any::<[bool; 4]>()).prop_map(
+            |(words, ignore_whitespace, search, gap_open, replace, [hits_first, text_first, group_skips, left_first])| Options {
+                costs: Costs { gap_open, replace, transpose: replace, ..Costs::default() },
+                tokens: if words { Tokens::Words } else { Tokens::Chars },
+                ignore_whitespace,
+                search,
+                tie_break: TieBreak { hits_first, text_first, group_skips, left_first },
+                ..Options::default()
+            }
+        )
+    }
+
+    proptest! {
+        #[test]
+        fn same_as_table(
+            pattern_str in r"(\(a\|bc\|\)\*|[ab ]|x[ab]\*|\\b|\(\?i:b\)|\(x\)\+|\(ab\)\{1,2\}){0,5}",
+            text_str in "[abcx ]{0,12}",
+            options in options(),
+        ) {
+            let pattern = parse_pattern(&pattern_str, false).unwrap().desugar();
+            let text = if options.tokens == Tokens::Words { words(&text_str) } else { text(&text_str) };
+            let expected = solve(&pattern, &text, &options).unwrap();
+            let conf = Config::new(&pattern, &text, &options);
+            let actual = solve_with(&conf, &options, 2).unwrap();
+            prop_assert_eq!(expected, actual);
+        }
+    }
+}
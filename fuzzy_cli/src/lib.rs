@@ -1,5 +1,6 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use fuzzy;
+use fuzzy::{Band, Costs, Limits, Options, Output, Solver, TieBreak, Tokens};
 use fuzzy::error::Error;
 use std::fs;
 
@@ -15,20 +16,273 @@ pub struct Args {
     /// PATTERN and TEXT args are raw pattern/text values rather than file names
     #[arg(short, long)]
     inline: bool,
+
+    /// Print the text captured by each group in PATTERN, rather than the diff
+    #[arg(short, long)]
+    captures: bool,
+
+    /// Match PATTERN and TEXT one character, word, or line at a time
+    #[arg(short, long, value_enum, default_value_t = TokensArg::Chars)]
+    tokens: TokensArg,
+
+    /// Match any run of whitespace in PATTERN against any run of whitespace in TEXT
+    #[arg(short = 'w', long)]
+    ignore_whitespace: bool,
+
+    /// Ignore case in PATTERN, as if it started with (?i)
+    #[arg(long)]
+    ignore_case: bool,
+
+    /// Find the best match for PATTERN anywhere in TEXT, and print the range of matched characters
+    /// before the diff
+    #[arg(short, long)]
+    search: bool,
+
+    /// Find every non-overlapping match of PATTERN in TEXT which costs at most MAX_COST, and print
+    /// the range of matched characters before the diff or captures of each one
+    #[arg(long, value_name = "MAX_COST")]
+    find_all: Option<usize>,
+
+    /// Print up to LIMIT distinct matches which are as good as the best match, each after a line
+    /// with its cost
+    #[arg(long, value_name = "LIMIT", conflicts_with_all = ["find_all", "k_best"])]
+    all_optimal: Option<usize>,
+
+    /// Print the K best distinct matches in order of increasing cost, each after a line with its
+    /// cost
+    #[arg(long, value_name = "K", conflicts_with = "find_all")]
+    k_best: Option<usize>,
+
+    /// Give up as soon as the match costs more than MAX_COST
+    #[arg(long, value_name = "MAX_COST")]
+    max_cost: Option<usize>,
+
+    /// Give up after the solver takes this many steps [default: 1000000000]
+    #[arg(long, value_name = "STEPS")]
+    max_steps: Option<usize>,
+
+    /// Give up before matching if the solver needs more than this many nodes in memory
+    #[arg(long, value_name = "NODES")]
+    max_nodes: Option<usize>,
+
+    /// Find the match with a fast table solver, or a slower solver which needs far less memory
+    #[arg(long, value_enum, default_value_t = SolverArg::Table)]
+    solver: SolverArg,
+
+    /// Only consider matches where TEXT stays within WIDTH characters of the text PATTERN expects
+    #[arg(long, value_name = "WIDTH")]
+    band: Option<usize>,
+
+    /// Widen the band until the match is guaranteed to be optimal
+    #[arg(long, requires = "band")]
+    adaptive_band: bool,
+
+    /// Change how we choose between matches with the same cost, e.g. group-skips,text-first
+    #[arg(long, value_enum, value_name = "PREFERENCES", value_delimiter = ',')]
+    tie_break: Vec<TieBreakArg>,
+
+    /// Cost of skipping a literal character in PATTERN [default: 1]
+    #[arg(long, value_name = "COST")]
+    skip_lit_cost: Option<usize>,
+
+    /// Cost of skipping a character class in PATTERN, e.g. [a-z] [default: 1]
+    #[arg(long, value_name = "COST")]
+    skip_class_cost: Option<usize>,
+
+    /// Cost of skipping a character in TEXT [default: 1]
+    #[arg(long, value_name = "COST")]
+    skip_text_cost: Option<usize>,
+
+    /// Cost of matching a literal character in PATTERN [default: 0]
+    #[arg(long, value_name = "COST")]
+    hit_lit_cost: Option<usize>,
+
+    /// Cost of matching a character class in PATTERN [default: 0]
+    #[arg(long, value_name = "COST")]
+    hit_class_cost: Option<usize>,
+
+    /// Cost of matching a case-insensitive literal in PATTERN against different case [default: 0]
+    #[arg(long, value_name = "COST")]
+    hit_case_cost: Option<usize>,
+
+    /// Cost of violating an anchor or word boundary in PATTERN [default: 1]
+    #[arg(long, value_name = "COST")]
+    fail_look_cost: Option<usize>,
+
+    /// Extra cost of starting a run of skipped characters in PATTERN or TEXT [default: 0]
+    #[arg(long, value_name = "COST")]
+    gap_open_cost: Option<usize>,
+
+    /// Allow replacing a character in PATTERN with a character in TEXT at this cost
+    #[arg(long, value_name = "COST")]
+    replace_cost: Option<usize>,
+
+    /// Allow matching two characters in PATTERN against the next two characters in TEXT in reverse
+    /// order at this cost
+    #[arg(long, value_name = "COST")]
+    transpose_cost: Option<usize>,
+
+    /// Characters which cannot be skipped in PATTERN or TEXT, e.g. '"'
+    #[arg(long, value_name = "CHARS")]
+    protect: Option<String>,
+
+    /// Allow skipping protected characters at this cost, rather than forbidding it
+    #[arg(long, value_name = "COST")]
+    skip_protected_cost: Option<usize>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum TokensArg {
+    Chars,
+    Words,
+    Lines,
+}
+
+#[derive(ValueEnum, Clone, Copy, Eq, PartialEq, Debug)]
+enum TieBreakArg {
+    /// Skip before matching, so matches are as late as possible
+    SkipsFirst,
+    /// Skip TEXT before skipping PATTERN
+    TextFirst,
+    /// Prefer fewer runs of skipped characters
+    GroupSkips,
+    /// Prefer the right side of an alternative
+    RightFirst,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum SolverArg {
+    Table,
+    LinearSpace,
+}
+
+impl From<SolverArg> for Solver {
+    fn from(arg: SolverArg) -> Solver {
+        match arg {
+            SolverArg::Table => Solver::Table,
+            SolverArg::LinearSpace => Solver::LinearSpace,
+        }
+    }
+}
+
+impl From<TokensArg> for Tokens {
+    fn from(arg: TokensArg) -> Tokens {
+        match arg {
+            TokensArg::Chars => Tokens::Chars,
+            TokensArg::Words => Tokens::Words,
+            TokensArg::Lines => Tokens::Lines,
+        }
+    }
+}
+
+impl Args {
+    fn options(&self) -> Options {
+        Options {
+            costs: self.costs(),
+            limits: self.limits(),
+            solver: Solver::from(self.solver),
+            tokens: Tokens::from(self.tokens),
+            ignore_whitespace: self.ignore_whitespace,
+            case_insensitive: self.ignore_case,
+            search: self.search,
+            max_cost: self.max_cost,
+            band: self.band.map(|width| Band { width, adaptive: self.adaptive_band }),
+            tie_break: self.tie_break(),
+        }
+    }
+
+    fn tie_break(&self) -> TieBreak {
+        let default = TieBreak::default();
+        let has = |arg: TieBreakArg| self.tie_break.contains(&arg);
+        TieBreak {
+            hits_first: default.hits_first && !has(TieBreakArg::SkipsFirst),
+            text_first: default.text_first || has(TieBreakArg::TextFirst),
+            group_skips: default.group_skips || has(TieBreakArg::GroupSkips),
+            left_first: default.left_first && !has(TieBreakArg::RightFirst),
+        }
+    }
+
+    fn limits(&self) -> Limits {
+        let default = Limits::default();
+        Limits {
+            max_steps: self.max_steps.unwrap_or(default.max_steps),
+            max_nodes: self.max_nodes.or(default.max_nodes),
+        }
+    }
+
+    fn costs(&self) -> Costs {
+        let default = Costs::default();
+        Costs {
+            skip_lit: self.skip_lit_cost.unwrap_or(default.skip_lit),
+            skip_class: self.skip_class_cost.unwrap_or(default.skip_class),
+            skip_text: self.skip_text_cost.unwrap_or(default.skip_text),
+            hit_lit: self.hit_lit_cost.unwrap_or(default.hit_lit),
+            hit_class: self.hit_class_cost.unwrap_or(default.hit_class),
+            hit_case: self.hit_case_cost.unwrap_or(default.hit_case),
+            fail_look: self.fail_look_cost.unwrap_or(default.fail_look),
+            gap_open: self.gap_open_cost.unwrap_or(default.gap_open),
+            replace: self.replace_cost.or(default.replace),
+            transpose: self.transpose_cost.or(default.transpose),
+            protected: self.protect.as_ref().map_or(default.protected, |chars| chars.chars().collect()),
+            skip_protected: self.skip_protected_cost.or(default.skip_protected),
+        }
+    }
 }
 
 pub fn run(args: Args) -> Result<String, Error> {
+    let options = args.options();
     let pattern_regex = if args.inline {
-        args.pattern
+        args.pattern.clone()
     } else {
-        fs::read_to_string(args.pattern)?
+        fs::read_to_string(&args.pattern)?
     };
     let text = if args.inline {
-        args.text
+        args.text.clone()
     } else {
-        fs::read_to_string(args.text)?
+        fs::read_to_string(&args.text)?
     };
 
-    let output = fuzzy::fuzzy_match(pattern_regex, text)?;
-    Ok(format!("{}", output))
+    if let Some(max_cost) = args.find_all {
+        let outputs = fuzzy::fuzzy_find_iter(pattern_regex, text, &options, max_cost)?;
+        let formatted: Result<Vec<String>, Error> = outputs
+            .map(|output| output.map(|output| format_ranged(&args, &output)))
+            .collect();
+        Ok(formatted?.join("\n"))
+    } else if let Some(limit) = args.all_optimal {
+        let outputs = fuzzy::fuzzy_match_all(pattern_regex, text, &options, limit)?;
+        Ok(format_alternatives(&args, &outputs))
+    } else if let Some(k) = args.k_best {
+        let outputs = fuzzy::fuzzy_match_k_best(pattern_regex, text, &options, k)?;
+        Ok(format_alternatives(&args, &outputs))
+    } else {
+        let output = fuzzy::fuzzy_match(pattern_regex, text, &options)?;
+        if args.search && !args.captures {
+            Ok(format_ranged(&args, &output))
+        } else {
+            Ok(format_output(&args, &output))
+        }
+    }
+}
+
+/// Formats each match after a line with its cost.
+fn format_alternatives(args: &Args, outputs: &[Output]) -> String {
+    let formatted: Vec<String> = outputs.iter()
+        .map(|output| {
+            let view = if args.search && !args.captures { format_ranged(args, output) } else { format_output(args, output) };
+            format!("cost {}\n{}", output.score, view)
+        })
+        .collect();
+    formatted.join("\n")
+}
+
+fn format_ranged(args: &Args, output: &Output) -> String {
+    format!("{}..{}\n{}", output.range.start, output.range.end, format_output(args, output))
+}
+
+fn format_output(args: &Args, output: &Output) -> String {
+    if args.captures {
+        format!("{}", output.captures)
+    } else {
+        format!("{}", output.diff)
+    }
 }
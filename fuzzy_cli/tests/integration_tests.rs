@@ -3,7 +3,6 @@ use tempfile::NamedTempFile;
 use std::io::{self, Write};
 
 #[test]
-#[should_panic] // TODO support empty patterns in the regex parser
 fn match_empty() {
     let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
 
@@ -16,6 +15,45 @@ fn match_empty() {
         .success();
 }
 
+#[test]
+fn match_empty_alternative() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("a(b|)()c")
+        .arg("ac")
+        .assert()
+        .stdout("ac\n")
+        .success();
+}
+
+#[test]
+fn fail_empty() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("")
+        .arg("ab")
+        .assert()
+        .stdout("{+ab+}\n")
+        .success();
+}
+
+#[test]
+fn match_unicode() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg(r"Copyright © [0-9]{4} José Müller")
+        .arg("Copyright © 2023 Josè Müller")
+        .assert()
+        .stdout("Copyright © 2023 Jos[-é-]{+è+} Müller\n")
+        .success();
+}
+
 #[test]
 fn readme_hello_world() {
     let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
@@ -120,6 +158,539 @@ fn readme_year() {
         .success();
 }
 
+#[test]
+fn match_anchors() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg(r"(?m)^key = \b[a-z]*$\n^key = \b[a-z]*$")
+        .arg("key = a\nkey = b")
+        .assert()
+        .stdout("key = a\nkey = b\n")
+        .success();
+}
+
+#[test]
+fn costs_skip_text() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--skip-text-cost")
+        .arg("3")
+        .arg("x(abc|)")
+        .arg("xa")
+        .assert()
+        .stdout("xa[-bc-]\n")
+        .success();
+}
+
+#[test]
+fn costs_default() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("x(abc|)")
+        .arg("xa")
+        .assert()
+        .stdout("x{+a+}\n")
+        .success();
+}
+
+#[test]
+fn protect_forbidden() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--protect")
+        .arg("\"")
+        .arg("a\"b")
+        .arg("ab\"")
+        .assert()
+        .stdout("a{+b+}\"[-b-]\n")
+        .success();
+}
+
+#[test]
+fn protect_impossible() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--protect")
+        .arg("\"")
+        .arg("a")
+        .arg("\"")
+        .assert()
+        .failure();
+}
+
+#[test]
+fn protect_cost() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--protect")
+        .arg("\"")
+        .arg("--skip-protected-cost")
+        .arg("1")
+        .arg("a\"b")
+        .arg("ab\"")
+        .assert()
+        .stdout("a[-\"-]b{+\"+}\n")
+        .success();
+}
+
+#[test]
+fn tokens_words() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--tokens")
+        .arg("words")
+        .arg("hi you")
+        .arg("hi yo")
+        .assert()
+        .stdout("hi [-you-]{+yo+}\n")
+        .success();
+}
+
+#[test]
+fn tokens_lines() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--tokens")
+        .arg("lines")
+        .arg(r#"name = "fuzzy"\nversion = "0\.1\.0"\n"#)
+        .arg("name = \"fuzzy\"\nversion = \"0.2.0\"\n")
+        .assert()
+        .stdout("name = \"fuzzy\"\n[-version = \"0.1.0\"\n-]{+version = \"0.2.0\"\n+}\n")
+        .success();
+}
+
+#[test]
+fn ignore_whitespace() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--ignore-whitespace")
+        .arg("to deal in the Software")
+        .arg("to deal\nin  the Software")
+        .assert()
+        .stdout("to deal\nin  the Software\n")
+        .success();
+}
+
+#[test]
+fn ignore_case() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--ignore-case")
+        .arg("Hello World")
+        .arg("hello world")
+        .assert()
+        .stdout("[~H~]{~h~}ello [~W~]{~w~}orld\n")
+        .success();
+}
+
+#[test]
+fn ignore_case_inline() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("Hello (?i:World)")
+        .arg("hello world")
+        .assert()
+        .stdout("[-H-]{+h+}ello [~W~]{~w~}orld\n")
+        .success();
+}
+
+#[test]
+fn ignore_case_cost() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--ignore-case")
+        .arg("--hit-case-cost")
+        .arg("3")
+        .arg("ab")
+        .arg("AB")
+        .assert()
+        .stdout("[-ab-]{+AB+}\n")
+        .success();
+}
+
+#[test]
+fn replace_cost() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--replace-cost")
+        .arg("1")
+        .arg("version = [0-9]\\.[0-9]")
+        .arg("version = 1,2")
+        .assert()
+        .stdout("version = 1[=.=]{=,=}2\n")
+        .success();
+}
+
+#[test]
+fn transpose_cost() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--transpose-cost")
+        .arg("1")
+        .arg("the receipt")
+        .arg("teh reciept")
+        .assert()
+        .stdout("t[%he%]{%eh%} rec[%ei%]{%ie%}pt\n")
+        .success();
+}
+
+#[test]
+fn gap_open_cost() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--gap-open-cost")
+        .arg("2")
+        .arg("a fine day")
+        .arg("a fairly fine day")
+        .assert()
+        .stdout("a f{+airly f+}ine day\n")
+        .success();
+}
+
+#[test]
+fn search() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--search")
+        .arg("hello")
+        .arg("xx helo yy")
+        .assert()
+        .stdout("3..7\nhel[-l-]o\n")
+        .success();
+}
+
+#[test]
+fn search_captures() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--search")
+        .arg("--captures")
+        .arg("version = \"([0-9.]*)\"")
+        .arg("name = \"fuzzy\"\nversion = \"0.1.0\"\n")
+        .assert()
+        .stdout("1: \"0.1.0\"\n")
+        .success();
+}
+
+#[test]
+fn find_all() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--find-all")
+        .arg("1")
+        .arg("fn stub\\(\\) \\{\\}")
+        .arg("fn stub() {}\nfn main() {}\nfn stb() {}\nfn stub(x) {}\n")
+        .assert()
+        .stdout("0..12\nfn stub() {}\n26..37\nfn st[-u-]b() {}\n38..51\nfn stub({+x+}) {}\n")
+        .success();
+}
+
+#[test]
+fn find_all_captures() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--find-all")
+        .arg("0")
+        .arg("--captures")
+        .arg("v=([0-9])")
+        .arg("v=1 v=2 w=3 v=4")
+        .assert()
+        .stdout("0..3\n1: \"1\"\n4..7\n1: \"2\"\n12..15\n1: \"4\"\n")
+        .success();
+}
+
+#[test]
+fn all_optimal() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--all-optimal")
+        .arg("5")
+        .arg("ab")
+        .arg("ba")
+        .assert()
+        .stdout("cost 2\n[-a-]b{+a+}\ncost 2\n{+b+}a[-b-]\n")
+        .success();
+}
+
+#[test]
+fn k_best_distinct() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--k-best")
+        .arg("3")
+        .arg("abc")
+        .arg("xbz")
+        .assert()
+        .stdout("cost 4\n[-a-]{+x+}b[-c-]{+z+}\ncost 6\n[-abc-]{+xbz+}\n")
+        .success();
+}
+
+#[test]
+fn k_best() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--k-best")
+        .arg("2")
+        .arg("colou?r")
+        .arg("color")
+        .assert()
+        .stdout("cost 0\ncolor\ncost 1\ncolo[-u-]r\n")
+        .success();
+}
+
+#[test]
+fn k_best_linear_space() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--solver")
+        .arg("linear-space")
+        .arg("--k-best")
+        .arg("2")
+        .arg("ab")
+        .arg("ba")
+        .assert()
+        .stderr("Error: SeveralMatchesNeedTable\n")
+        .failure();
+}
+
+#[test]
+fn k_best_search() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--search")
+        .arg("--k-best")
+        .arg("2")
+        .arg("ab")
+        .arg("xaby")
+        .assert()
+        .stdout("cost 0\n1..3\nab\ncost 1\n1..2\na[-b-]\n")
+        .success();
+}
+
+#[test]
+fn max_cost_within() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--max-cost")
+        .arg("4")
+        .arg("hello world")
+        .arg("help word")
+        .assert()
+        .stdout("hel[-lo-]{+p+} wor[-l-]d\n")
+        .success();
+}
+
+#[test]
+fn max_cost_exceeded() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--max-cost")
+        .arg("3")
+        .arg("hello world")
+        .arg("help word")
+        .assert()
+        .stderr("Error: NoMatchWithinMaxCost(3)\n")
+        .failure();
+}
+
+#[test]
+fn max_nodes() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--max-nodes")
+        .arg("10")
+        .arg("abc")
+        .arg("abcd")
+        .assert()
+        .stderr("Error: ExceededMaxNodes(10)\n")
+        .failure();
+}
+
+#[test]
+fn band_exceeded() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--band")
+        .arg("3")
+        .arg("hello world")
+        .arg("well, hello world")
+        .assert()
+        .stderr("Error: NoMatchWithinBand(3)\n")
+        .failure();
+}
+
+#[test]
+fn adaptive_band() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--band")
+        .arg("3")
+        .arg("--adaptive-band")
+        .arg("hello world")
+        .arg("well, hello world")
+        .assert()
+        .success()
+        .stdout("{+well, +}hello world\n");
+}
+
+#[test]
+fn tie_break() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--tie-break")
+        .arg("group-skips,skips-first")
+        .arg("abcb")
+        .arg("b")
+        .assert()
+        .success()
+        .stdout("[-abc-]b\n");
+}
+
+#[test]
+fn tie_break_default() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("abcb")
+        .arg("b")
+        .assert()
+        .success()
+        .stdout("[-a-]b[-cb-]\n");
+}
+
+#[test]
+fn linear_space_solver() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--solver")
+        .arg("linear-space")
+        .arg("(<([0-9]*,)*[0-9]*> )*<([0-9]*,)*[0-9]*>")
+        .arg("<12,34,56> <789> <")
+        .assert()
+        .stdout("<12,34,56> <789> <[->-]\n")
+        .success();
+}
+
+#[test]
+fn captures_cargo_name() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--captures")
+        .arg(r#"name = "([a-z_]*)"\nversion = "([0-9.]*)""#)
+        .arg("name = \"fuzzy_cli\"\nversion = \"0.1.0\"")
+        .assert()
+        .stdout("1: \"fuzzy_cli\"\n2: \"0.1.0\"\n")
+        .success();
+}
+
+#[test]
+fn captures_named() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--captures")
+        .arg(r#"name = "(?P<name>[a-z_]*)"\n(version) = "(?P<version>[0-9.]*)""#)
+        .arg("name = \"fuzzy_cli\"\nversion = \"0.1.0\"")
+        .assert()
+        .stdout("name: \"fuzzy_cli\"\n2: \"version\"\nversion: \"0.1.0\"\n")
+        .success();
+}
+
+#[test]
+fn captures_lazy() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--search")
+        .arg("--captures")
+        .arg(r#""(.*?)""#)
+        .arg(r#"say "hi" and "bye""#)
+        .assert()
+        .stdout("1: \"hi\"\n")
+        .success();
+}
+
+#[test]
+fn captures_skipped_text() {
+    let mut cmd = Command::cargo_bin("fuzzy_cli").unwrap();
+
+    cmd
+        .arg("-i")
+        .arg("--captures")
+        .arg("<([0-9]*)>")
+        .arg("<12a3>")
+        .assert()
+        .stdout("1: \"12a3\"\n")
+        .success();
+}
+
 #[test]
 fn smoke_readme_license() -> Result<(), io::Error>{
     let mut pattern = NamedTempFile::new()?;
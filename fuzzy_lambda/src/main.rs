@@ -1,8 +1,15 @@
 use fuzzy;
+use fuzzy::{Limits, Options};
 use fuzzy::diff_output::Chunk;
+use fuzzy::capture_output::{Capture, Captures};
 
 use lambda_http::{run, service_fn, Body, Error, Request, Response};
 use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+
+/// The most nodes the solver can allocate. Each node takes 16 bytes, so this keeps the solver's
+/// table under 1GB, and we return an error for larger inputs instead of running out of memory.
+const MAX_NODES: usize = 60_000_000;
 
 #[derive(Deserialize)]
 struct Args {
@@ -16,6 +23,8 @@ struct Args {
 #[derive(Serialize)]
 struct Out {
     trace: Vec<OutChunk>,
+    captures: Vec<Option<OutCapture>>,
+    named_captures: BTreeMap<String, OutCapture>,
 }
 
 #[derive(Serialize)]
@@ -23,6 +32,13 @@ enum OutChunk {
     Same(String),
     Taken(String),
     Added(String),
+    CaseTaken(String),
+    CaseAdded(String),
+    ReplaceTaken(String),
+    ReplaceAdded(String),
+    SwapTaken(String),
+    SwapAdded(String),
+    FailedLook(String),
 }
 
 impl OutChunk {
@@ -42,17 +58,67 @@ impl OutChunk {
                     OutChunk::Taken(diff.taken.iter().collect()),
                     OutChunk::Added(diff.added.iter().collect()),
                 ],
+                Chunk::Case(case) => vec![
+                    OutChunk::CaseTaken(case.taken.iter().collect()),
+                    OutChunk::CaseAdded(case.added.iter().collect()),
+                ],
+                Chunk::Replace(replace) => vec![
+                    OutChunk::ReplaceTaken(replace.taken.iter().collect()),
+                    OutChunk::ReplaceAdded(replace.added.iter().collect()),
+                ],
+                Chunk::Swap(swap) => vec![
+                    OutChunk::SwapTaken(swap.taken.iter().collect()),
+                    OutChunk::SwapAdded(swap.added.iter().collect()),
+                ],
+                Chunk::FailLook(look) => vec![
+                    OutChunk::FailedLook(look.to_string()),
+                ],
             }
         ).collect()
     }
 }
 
+#[derive(Serialize)]
+struct OutCapture {
+    start: usize,
+    end: usize,
+    text: String,
+}
+
+impl OutCapture {
+    fn from(captures: &Captures) -> Vec<Option<OutCapture>> {
+        captures.groups.iter().map(|group| group.as_ref().map(OutCapture::new)).collect()
+    }
+
+    fn from_named(captures: &Captures) -> BTreeMap<String, OutCapture> {
+        captures.named().into_iter()
+            .map(|(name, capture)| (name.to_string(), OutCapture::new(capture)))
+            .collect()
+    }
+
+    fn new(capture: &Capture) -> OutCapture {
+        OutCapture {
+            start: capture.start,
+            end: capture.end,
+            text: capture.text.clone(),
+        }
+    }
+}
+
 async fn function_handler(event: Request) -> Result<Response<Body>, Error> {
     let body_str = std::str::from_utf8(event.body())?;
     let args = serde_json::from_str::<Args>(body_str)?;
-    let output = fuzzy::fuzzy_match(args.pattern, args.text)?;
+    let options = Options {
+        limits: Limits { max_nodes: Some(MAX_NODES), ..Limits::default() },
+        ..Options::default()
+    };
+    let output = fuzzy::fuzzy_match(args.pattern, args.text, &options)?;
 
-    let body = Out { trace: OutChunk::from(&output.chunks) };
+    let body = Out {
+        trace: OutChunk::from(&output.diff.chunks),
+        captures: OutCapture::from(&output.captures),
+        named_captures: OutCapture::from_named(&output.captures),
+    };
     let body_json = serde_json::to_string(&body)?;
 
     let resp = Response::builder()